@@ -0,0 +1,307 @@
+use super::pgntocmbr::{halfmoves_before, starting_position, variant_from_headers, RESULT_TO_CHAR};
+use super::structs::*;
+use super::{CmbrMove, CmbrPackedPosition, CmbrWriter, SanToCmbrMvConvertor};
+use crate::error::{LibCmbrError, LibCmbrErrorType};
+use crate::pgn::VariationPointerT;
+
+use shakmaty::san::Suffix;
+use shakmaty::Move;
+
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+
+/// A variation that is being built
+#[derive(Debug, Clone)]
+struct CmbrBuilderFrame<P> {
+    pointer: VariationPointerT,
+    board: P,
+    /// The position before the last move, which its alternatives start from. `None` before the
+    /// first move
+    previous_board: Option<P>,
+    half_move: u16,
+    /// The variations opened after the last move. See `CmbrComment::variations_before`
+    variations: u16,
+}
+
+/// Builds a `CmbrGame` move by move, without going through PGN. Moves are checked to be legal as
+/// they're pushed. Variations are opened after the move they're an alternative to, and closed
+/// to continue their parent, like parentheses in PGN. `P` has to match the variant of the game
+#[derive(Debug, Clone)]
+pub struct CmbrGameBuilder<P: CmbrPosition> {
+    game: CmbrGame,
+    with_positions: bool,
+    /// The positions the game reached, keyed like `CmbrFile::encountered_positions`
+    positions: HashMap<PositionKey, CmbrPackedPosition>,
+    /// The open variations. The main variation is always first
+    frames: Vec<CmbrBuilderFrame<P>>,
+}
+
+impl<P: CmbrPosition + 'static> CmbrGameBuilder<P> {
+    /// Starts a game. The variant and the starting position are read from the `Variant` and
+    /// `FEN` headers, like in `CmbrFile::from_ast`. Fails with `VariantMismatch` if `P` isn't
+    /// the position type of the variant (See `with_position_type`), and with `InvalidFen` if the
+    /// FEN isn't valid. If `with_positions` isn't set, the game has no position tables
+    pub fn new(headers: Vec<(String, String)>, with_positions: bool) -> Result<Self, LibCmbrError> {
+        let mut game = CmbrGame::new();
+        game.variant = variant_from_headers(&headers);
+        game.headers = headers;
+
+        if !with_position_type!(game.variant, V => TypeId::of::<V>() == TypeId::of::<P>()) {
+            return Err(LibCmbrError::new(LibCmbrErrorType::VariantMismatch));
+        }
+
+        let (board, starting_fen) = starting_position::<P>(&game.headers, game.castling_mode())
+            .map_err(|_| LibCmbrError::new(LibCmbrErrorType::InvalidFen))?;
+        game.starting_position = starting_fen;
+
+        let half_move = halfmoves_before(&board)?;
+        game.variations.insert(0, CmbrVariation::new(half_move));
+
+        let mut builder = Self {
+            game,
+            with_positions,
+            positions: HashMap::new(),
+            frames: Vec::with_capacity(1),
+        };
+
+        builder.record_position(half_move as MoveId, &board);
+        builder.frames.push(CmbrBuilderFrame {
+            pointer: 0,
+            board,
+            previous_board: None,
+            half_move,
+            variations: 0,
+        });
+
+        return Ok(builder);
+    }
+
+    fn frame(&self) -> &CmbrBuilderFrame<P> {
+        // SAFE: Safe. The main variation is never closed
+        return unsafe { self.frames.last().unwrap_unchecked() };
+    }
+
+    fn variation(&mut self) -> &mut CmbrVariation {
+        let pointer = self.frame().pointer;
+
+        // SAFE: Safe. Every open variation is in `variations`
+        return unsafe { self.game.variations.get_mut(&pointer).unwrap_unchecked() };
+    }
+
+    fn record_position(&mut self, move_id: MoveId, board: &P) {
+        if !self.with_positions {
+            return;
+        }
+
+        let key = insert_position(
+            &mut self.positions,
+            position_hash(board),
+            &CmbrPackedPosition::from_position(board),
+        );
+        self.game.encountered_positions.insert(move_id, key);
+    }
+
+    /// The position after the last move of the current variation
+    pub fn board(&self) -> &P {
+        return &self.frame().board;
+    }
+
+    /// The pointer of the current variation. 0 in the main variation
+    pub fn current_variation(&self) -> VariationPointerT {
+        return self.frame().pointer;
+    }
+
+    pub fn push_header(&mut self, key: &str, value: &str) {
+        self.game.headers.push((key.to_owned(), value.to_owned()));
+    }
+
+    /// Adds an escape comment, which is written before the tags
+    pub fn push_escape_comment(&mut self, comment: &str) {
+        self.game.escape_comments.push(comment.to_owned());
+    }
+
+    /// Sets the result from its PGN form (`1-0`, `0-1`, `1/2-1/2` or `*`). Fails with
+    /// `UnknownResult` otherwise
+    pub fn set_result(&mut self, result: &str) -> Result<(), LibCmbrError> {
+        let result = RESULT_TO_CHAR
+            .get(result.as_bytes())
+            .ok_or(LibCmbrError::new(LibCmbrErrorType::UnknownResult))?;
+
+        self.game.result = *result;
+        return Ok(());
+    }
+
+    /// Plays a move in the current variation. The check and mate flags are set from the position
+    /// it reaches. Fails with `IllegalCmbrMv` if the move isn't legal
+    pub fn push_move(&mut self, shakmaty_move: &Move) -> Result<(), LibCmbrError> {
+        let frame = self.frame();
+        if !frame.board.is_legal(shakmaty_move) {
+            return Err(LibCmbrError::new(LibCmbrErrorType::IllegalCmbrMv));
+        }
+
+        let mut board = frame.board.clone();
+        let color = board.turn();
+        board.play_unchecked(shakmaty_move);
+
+        let suffix = Suffix::from_position(&board);
+        let cmbr = SanToCmbrMvConvertor::move_to_cmbr(shakmaty_move, color, suffix);
+
+        return self.play(cmbr, board);
+    }
+
+    /// Passes the turn in the current variation. Fails with `IllegalCmbrMv` if the side to move
+    /// is in check
+    pub fn push_null_move(&mut self) -> Result<(), LibCmbrError> {
+        let mut board = self.frame().board.clone();
+        let color = board.turn();
+        SanToCmbrMvConvertor::play_null_move(&mut board)?;
+
+        return self.play(CmbrMove::NullMove { color }.into(), board);
+    }
+
+    fn play(&mut self, cmbr: CmbrMv, board: P) -> Result<(), LibCmbrError> {
+        self.variation().moves.push(cmbr);
+
+        // SAFE: Safe. The main variation is never closed
+        let frame = unsafe { self.frames.last_mut().unwrap_unchecked() };
+        frame.previous_board = Some(std::mem::replace(&mut frame.board, board));
+        frame.half_move += 1;
+        frame.variations = 0;
+
+        let move_id = (frame.pointer << 16) | frame.half_move as MoveId;
+        let board = frame.board.clone();
+        self.record_position(move_id, &board);
+
+        return Ok(());
+    }
+
+    /// Attaches a NAG to the last move of the current variation. Fails with `InvalidNag` if the
+    /// current variation has no moves yet
+    pub fn push_nag(&mut self, nag: u8) -> Result<(), LibCmbrError> {
+        if self.frame().previous_board.is_none() {
+            return Err(LibCmbrError::new(LibCmbrErrorType::InvalidNag));
+        }
+
+        self.variation().moves.push(CmbrMove::Nag(nag).into());
+        return Ok(());
+    }
+
+    /// Adds a comment after the last move of the current variation, or before its first move if
+    /// it has no moves yet. See `CmbrCommentKind`
+    pub fn push_comment(&mut self, kind: u8, comment: &str) {
+        let frame = self.frame();
+        let comment = CmbrComment {
+            half_move: frame.half_move,
+            kind,
+            variations_before: frame.variations,
+            text: comment.to_owned(),
+        };
+
+        self.variation().comments.push(comment);
+    }
+
+    /// Opens a variation that is an alternative to the last move of the current one, and returns
+    /// its pointer. Fails with `MissingPosition` if the current variation has no moves yet, and
+    /// with `TooManyVariations` if the game already has 65535 variations
+    pub fn start_variation(&mut self) -> Result<VariationPointerT, LibCmbrError> {
+        let frame = self.frame();
+        let board = frame
+            .previous_board
+            .clone()
+            .ok_or(LibCmbrError::new(LibCmbrErrorType::MissingPosition))?;
+
+        // Variations are numbered in the order they're opened
+        let pointer = self.game.variations.len() as VariationPointerT;
+        if pointer > u16::MAX as VariationPointerT {
+            return Err(LibCmbrError::new(LibCmbrErrorType::TooManyVariations));
+        }
+
+        let starts_at = frame.half_move - 1;
+        let parent_move_id = (frame.pointer << 16) | starts_at as MoveId;
+
+        self.variation()
+            .moves
+            .push(CmbrMove::VariationPointer(pointer as u16).into());
+        // SAFE: Safe. The main variation is never closed
+        unsafe { self.frames.last_mut().unwrap_unchecked() }.variations += 1;
+        self.game
+            .variations
+            .insert(pointer, CmbrVariation::new(starts_at));
+
+        // A variation's starting position is stored under its own pointer as well
+        if let Some(key) = self
+            .game
+            .encountered_positions
+            .get(&parent_move_id)
+            .copied()
+        {
+            self.game
+                .encountered_positions
+                .insert((pointer << 16) | starts_at as MoveId, key);
+        }
+
+        self.frames.push(CmbrBuilderFrame {
+            pointer,
+            board,
+            previous_board: None,
+            half_move: starts_at,
+            variations: 0,
+        });
+
+        return Ok(pointer);
+    }
+
+    /// Closes the current variation, and continues its parent. Returns false in the main
+    /// variation. Fails with `EmptyVariation` if the variation has no moves
+    pub fn end_variation(&mut self) -> Result<bool, LibCmbrError> {
+        let frame = self.frame();
+
+        if frame.pointer == 0 {
+            return Ok(false);
+        }
+
+        if frame.previous_board.is_none() {
+            return Err(LibCmbrError::new(LibCmbrErrorType::EmptyVariation));
+        }
+
+        self.frames.pop();
+        return Ok(true);
+    }
+
+    /// Closes the open variations, and returns the game and the positions it reached (See
+    /// `CmbrWriter::push_game`)
+    pub fn finish(
+        mut self,
+    ) -> Result<(CmbrGame, HashMap<PositionKey, CmbrPackedPosition>), LibCmbrError> {
+        while self.end_variation()? {}
+
+        return Ok((self.game, self.positions));
+    }
+
+    /// Adds the game to `file`, after its last game, and returns the game's Id
+    pub fn append_to(self, file: &mut CmbrFile) -> Result<u32, LibCmbrError> {
+        let (mut game, positions) = self.finish()?;
+        let id = file.games.keys().max().map_or(0, |id| id + 1);
+
+        move_positions(
+            &mut game.encountered_positions,
+            &positions,
+            &mut file.encountered_positions,
+        );
+        file.games.insert(id, game);
+
+        return Ok(id);
+    }
+
+    /// Writes the game with `writer`, and returns the game's Id
+    pub fn write_to<W: Write>(self, writer: &mut CmbrWriter<W>) -> Result<u32, Box<dyn Error>> {
+        let (game, positions) = self.finish()?;
+        let id = writer.game_count();
+
+        writer.push_game(game, &positions)?;
+
+        return Ok(id);
+    }
+}
@@ -0,0 +1,337 @@
+use super::{CmbrMv, CmbrMvFlags, CmbrMvPiece};
+use crate::error::{LibCmbrError, LibCmbrErrorType};
+use crate::utils::extract_bits_from_num;
+
+use shakmaty::san::Suffix;
+use shakmaty::{CastlingSide, Color, Piece, Role, Square};
+
+/// A typed view over the bit layout of a CMBR-MV.
+///
+/// Converting a `CmbrMove` into a `CmbrMv` and back is lossless, and so is converting every
+/// valid `CmbrMv` into a `CmbrMove` and back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CmbrMove {
+    /// Any move that isn't a castle or a drop. `from` and `to` are never the same
+    Normal {
+        piece: Piece,
+        from: Square,
+        to: Square,
+        capture: bool,
+        promotion: Option<Role>,
+        suffix: Option<Suffix>,
+    },
+    Castle {
+        color: Color,
+        side: CastlingSide,
+        suffix: Option<Suffix>,
+    },
+    /// A piece dropped from the pocket (Crazyhouse). Encoded as a move whose from and to
+    /// squares are the same
+    Drop {
+        piece: Piece,
+        to: Square,
+        suffix: Option<Suffix>,
+    },
+    /// A null move, which only passes the turn. Encoded as a king move from a1 to a1
+    NullMove { color: Color },
+    /// A NAG attached to the previous move
+    Nag(u8),
+    /// A pointer to a variation that is an alternative to the previous move
+    VariationPointer(u16),
+}
+
+impl CmbrMove {
+    pub fn from(&self) -> Option<Square> {
+        return match self {
+            Self::Normal { from, .. } => Some(*from),
+            _ => None,
+        };
+    }
+
+    pub fn to(&self) -> Option<Square> {
+        return match self {
+            Self::Normal { to, .. } | Self::Drop { to, .. } => Some(*to),
+            _ => None,
+        };
+    }
+
+    /// The piece that is moved. For castles that's the king
+    pub fn piece(&self) -> Option<Piece> {
+        return match self {
+            Self::Normal { piece, .. } | Self::Drop { piece, .. } => Some(*piece),
+            Self::Castle { color, .. } => Some(Piece {
+                color: *color,
+                role: Role::King,
+            }),
+            _ => None,
+        };
+    }
+
+    pub fn is_capture(&self) -> bool {
+        return matches!(self, Self::Normal { capture: true, .. });
+    }
+
+    pub fn suffix(&self) -> Option<Suffix> {
+        return match self {
+            Self::Normal { suffix, .. }
+            | Self::Castle { suffix, .. }
+            | Self::Drop { suffix, .. } => *suffix,
+            _ => None,
+        };
+    }
+
+    /// Whether the move gives check. Checkmates are checks too
+    pub fn is_check(&self) -> bool {
+        return self.suffix().is_some();
+    }
+
+    pub fn is_mate(&self) -> bool {
+        return self.suffix() == Some(Suffix::Checkmate);
+    }
+
+    pub fn promotion(&self) -> Option<Role> {
+        return match self {
+            Self::Normal { promotion, .. } => *promotion,
+            _ => None,
+        };
+    }
+
+    pub fn castling_side(&self) -> Option<CastlingSide> {
+        return match self {
+            Self::Castle { side, .. } => Some(*side),
+            _ => None,
+        };
+    }
+
+    pub fn is_drop(&self) -> bool {
+        return matches!(self, Self::Drop { .. });
+    }
+
+    pub fn nag(&self) -> Option<u8> {
+        return match self {
+            Self::Nag(nag) => Some(*nag),
+            _ => None,
+        };
+    }
+
+    pub fn variation_pointer(&self) -> Option<u16> {
+        return match self {
+            Self::VariationPointer(pointer) => Some(*pointer),
+            _ => None,
+        };
+    }
+
+    pub fn is_null_move(&self) -> bool {
+        return matches!(self, Self::NullMove { .. });
+    }
+
+    /// Whether this is a move played on the board, and not a NAG or a variation pointer. Null
+    /// moves are moves too
+    pub fn is_move(&self) -> bool {
+        return matches!(
+            self,
+            Self::Normal { .. } | Self::Castle { .. } | Self::Drop { .. } | Self::NullMove { .. }
+        );
+    }
+
+    fn suffix_to_flag(suffix: Option<Suffix>) -> u8 {
+        return match suffix {
+            None => CmbrMvFlags::FlagNone,
+            Some(Suffix::Check) => CmbrMvFlags::FlagCheck,
+            Some(Suffix::Checkmate) => CmbrMvFlags::FlagMate,
+        };
+    }
+
+    fn color_to_piece_bits(color: Color) -> u8 {
+        return if color == Color::Black { 0b1000 } else { 0 };
+    }
+}
+
+impl From<CmbrMove> for CmbrMv {
+    fn from(cmbr_move: CmbrMove) -> Self {
+        let cmbr = match cmbr_move {
+            CmbrMove::Normal {
+                piece,
+                from,
+                to,
+                capture,
+                promotion,
+                suffix,
+            } => {
+                let mut flags = CmbrMove::suffix_to_flag(suffix);
+
+                if capture {
+                    flags |= CmbrMvFlags::FlagCapture;
+                }
+
+                flags |= match promotion {
+                    None => CmbrMvFlags::FlagNone,
+                    Some(Role::Knight) => CmbrMvFlags::FlagPromotesKnight,
+                    Some(Role::Bishop) => CmbrMvFlags::FlagPromotesBishop,
+                    Some(Role::Rook) => CmbrMvFlags::FlagPromotesRook,
+                    Some(Role::King) => CmbrMvFlags::FlagPromotesKing,
+                    Some(_) => CmbrMvFlags::FlagPromotesQueen,
+                };
+
+                let piece_bits =
+                    (piece.role as u8 - 1) | CmbrMove::color_to_piece_bits(piece.color);
+
+                flags as u32
+                    | (piece_bits as u32) << 8
+                    | (from as u32) << (8 + 4)
+                    | (to as u32) << (8 + 4 + 6)
+            }
+
+            CmbrMove::Castle {
+                color,
+                side,
+                suffix,
+            } => {
+                let piece_bits = match side {
+                    CastlingSide::KingSide => CmbrMvPiece::WhiteShortCastle,
+                    CastlingSide::QueenSide => CmbrMvPiece::WhiteLongCaslte,
+                } | CmbrMove::color_to_piece_bits(color);
+
+                CmbrMove::suffix_to_flag(suffix) as u32 | (piece_bits as u32) << 8
+            }
+
+            CmbrMove::Drop { piece, to, suffix } => {
+                let piece_bits =
+                    (piece.role as u8 - 1) | CmbrMove::color_to_piece_bits(piece.color);
+
+                CmbrMove::suffix_to_flag(suffix) as u32
+                    | (piece_bits as u32) << 8
+                    | (to as u32) << (8 + 4)
+                    | (to as u32) << (8 + 4 + 6)
+            }
+
+            CmbrMove::NullMove { color } => {
+                let piece_bits = (Role::King as u8 - 1) | CmbrMove::color_to_piece_bits(color);
+
+                (piece_bits as u32) << 8
+            }
+
+            CmbrMove::Nag(nag) => (nag as u32) << 8 | CmbrMvFlags::FlagNag as u32,
+
+            CmbrMove::VariationPointer(pointer) => {
+                (pointer as u32) << 8 | CmbrMvFlags::FlagIsVariationPointer as u32
+            }
+        };
+
+        return cmbr.into();
+    }
+}
+
+impl TryFrom<CmbrMv> for CmbrMove {
+    type Error = LibCmbrError;
+
+    /// Fails if `cmbr` isn't a bit pattern that any `CmbrMove` encodes to
+    fn try_from(cmbr: CmbrMv) -> Result<Self, Self::Error> {
+        let invalid = Err(LibCmbrError::new(LibCmbrErrorType::InvalidCmbrMv));
+
+        let cmbr = cmbr.to_u32();
+        let flags = cmbr as u8;
+
+        if flags == CmbrMvFlags::FlagIsVariationPointer {
+            return Ok(Self::VariationPointer(
+                extract_bits_from_num(cmbr, 16, 8) as u16
+            ));
+        }
+
+        if flags == CmbrMvFlags::FlagNag {
+            if cmbr >> 16 != 0 {
+                return invalid;
+            }
+
+            return Ok(Self::Nag(extract_bits_from_num(cmbr, 8, 8) as u8));
+        }
+
+        if flags & (CmbrMvFlags::FlagIsVariationPointer | CmbrMvFlags::FlagNag) != 0 {
+            return invalid;
+        }
+
+        let suffix = match flags & (CmbrMvFlags::FlagCheck | CmbrMvFlags::FlagMate) {
+            CmbrMvFlags::FlagNone => None,
+            CmbrMvFlags::FlagCheck => Some(Suffix::Check),
+            CmbrMvFlags::FlagMate => Some(Suffix::Checkmate),
+            _ => return invalid,
+        };
+
+        let promotion = match flags & CmbrMvFlags::FlagPromotesQueen {
+            CmbrMvFlags::FlagNone => None,
+            CmbrMvFlags::FlagPromotesKnight => Some(Role::Knight),
+            CmbrMvFlags::FlagPromotesBishop => Some(Role::Bishop),
+            CmbrMvFlags::FlagPromotesRook => Some(Role::Rook),
+            CmbrMvFlags::FlagPromotesQueen => Some(Role::Queen),
+            CmbrMvFlags::FlagPromotesKing => Some(Role::King),
+            _ => return invalid,
+        };
+
+        let capture = flags & CmbrMvFlags::FlagCapture != 0;
+        let piece_bits = extract_bits_from_num(cmbr, 4, 8) as u8;
+        let color = Color::from_white(piece_bits & 0b1000 == 0);
+
+        if piece_bits & CmbrMvPiece::WhiteShortCastle == CmbrMvPiece::WhiteShortCastle {
+            if capture || promotion.is_some() || cmbr >> 12 != 0 {
+                return invalid;
+            }
+
+            let side = if piece_bits & 1 == 0 {
+                CastlingSide::KingSide
+            } else {
+                CastlingSide::QueenSide
+            };
+
+            return Ok(Self::Castle {
+                color,
+                side,
+                suffix,
+            });
+        }
+
+        let role = [
+            Role::Pawn,
+            Role::Knight,
+            Role::Bishop,
+            Role::Rook,
+            Role::Queen,
+            Role::King,
+        ][(piece_bits & 0b0111) as usize];
+
+        if promotion.is_some() && role != Role::Pawn {
+            return invalid;
+        }
+
+        let from = Square::new(extract_bits_from_num(cmbr, 6, 12));
+        let to = Square::new(extract_bits_from_num(cmbr, 6, 18));
+
+        if from == to {
+            if role == Role::King {
+                if flags != CmbrMvFlags::FlagNone || from != Square::A1 {
+                    return invalid;
+                }
+
+                return Ok(Self::NullMove { color });
+            }
+
+            if capture || promotion.is_some() {
+                return invalid;
+            }
+
+            return Ok(Self::Drop {
+                piece: Piece { color, role },
+                to,
+                suffix,
+            });
+        }
+
+        return Ok(Self::Normal {
+            piece: Piece { color, role },
+            from,
+            to,
+            capture,
+            promotion,
+            suffix,
+        });
+    }
+}
@@ -0,0 +1,238 @@
+use super::{
+    with_position_type, CmbrComment, CmbrCommentKind, CmbrFile, CmbrGame, CmbrPosition,
+    CmbrVariation, DecodedCmbrMv, SanToCmbrMvConvertor,
+};
+
+use std::error::Error;
+use std::io::Write;
+use std::iter::Peekable;
+
+/// PGN export format recommends lines no longer than 80 characters
+const MAX_LINE_LENGTH: usize = 79;
+
+fn char_to_result(result: char) -> &'static str {
+    return match result {
+        'w' => "1-0",
+        'b' => "0-1",
+        'd' => "1/2-1/2",
+        _ => "*",
+    };
+}
+
+/// Collects movetext tokens and wraps them into lines
+struct MovetextWriter {
+    text: String,
+    line_length: usize,
+}
+
+impl MovetextWriter {
+    fn new() -> Self {
+        return Self {
+            text: String::with_capacity(512),
+            line_length: 0,
+        };
+    }
+
+    fn push(&mut self, token: &str) {
+        let glued = self.text.ends_with('(') || token == ")";
+
+        if self.line_length != 0 && !glued {
+            if self.line_length + 1 + token.len() > MAX_LINE_LENGTH {
+                self.text.push('\n');
+                self.line_length = 0;
+            } else {
+                self.text.push(' ');
+                self.line_length += 1;
+            }
+        }
+
+        self.text.push_str(token);
+        self.line_length += token.len();
+    }
+
+    /// Pushes a move, preceded by its move number if it's white's move or `needs_move_number`
+    /// is set
+    fn push_move(&mut self, san: &str, half_move: u16, needs_move_number: bool) {
+        let move_number = half_move / 2 + 1;
+        if half_move % 2 == 0 {
+            self.push(&format!("{move_number}."));
+        } else if needs_move_number {
+            self.push(&format!("{move_number}..."));
+        }
+
+        self.push(san);
+    }
+
+    /// Ends the current line. The next token starts a new one
+    fn end_line(&mut self) {
+        self.text.push('\n');
+        self.line_length = 0;
+    }
+
+    /// Pushes every comment attached before `half_move`, and the ones on it that come after at most
+    /// `variations` of its variations. Returns whether any were pushed
+    fn push_comments<'a, I>(
+        &mut self,
+        comments: &mut Peekable<I>,
+        half_move: u16,
+        variations: u16,
+    ) -> bool
+    where
+        I: Iterator<Item = &'a CmbrComment>,
+    {
+        let mut pushed = false;
+
+        while let Some(CmbrComment {
+            kind,
+            text: comment,
+            ..
+        }) = comments.next_if(|c| {
+            c.half_move < half_move
+                || (c.half_move == half_move && c.variations_before <= variations)
+        }) {
+            match *kind {
+                CmbrCommentKind::Line => {
+                    self.push(&format!(";{comment}"));
+                    self.end_line();
+                }
+
+                // Escape comments have to start at the beginning of a line
+                CmbrCommentKind::Escape => {
+                    if self.line_length != 0 {
+                        self.end_line();
+                    }
+
+                    self.push(&format!("%{comment}"));
+                    self.end_line();
+                }
+
+                // A `}` would end a brace comment early, so those are written as `;` comments
+                _ if comment.contains('}') => {
+                    for line in comment.lines() {
+                        self.push(&format!(";{line}"));
+                        self.end_line();
+                    }
+                }
+
+                _ => self.push(&format!("{{{comment}}}")),
+            }
+
+            pushed = true;
+        }
+
+        return pushed;
+    }
+}
+
+impl CmbrFile {
+    /// Writes every game in the file as PGN, ordered by game id
+    pub fn to_pgn<W: Write>(&self, writer: &mut W) -> Result<(), Box<dyn Error>> {
+        let mut game_ids: Vec<&u32> = self.games.keys().collect();
+        game_ids.sort_unstable();
+
+        for game_id in game_ids {
+            self.games[game_id].to_pgn(writer)?;
+            writeln!(writer)?;
+        }
+
+        return Ok(());
+    }
+}
+
+impl CmbrGame {
+    /// Writes the game (headers, movetext and result) as PGN
+    pub fn to_pgn<W: Write>(&self, writer: &mut W) -> Result<(), Box<dyn Error>> {
+        for comment in &self.escape_comments {
+            writeln!(writer, "%{comment}")?;
+        }
+
+        for (key, value) in &self.headers {
+            writeln!(writer, "[{key} \"{value}\"]")?;
+        }
+
+        writeln!(writer)?;
+
+        let mut movetext = MovetextWriter::new();
+
+        if let Some(main_variation) = self.variations.get(&0) {
+            with_position_type!(self.variant, P => {
+                self.variation_to_pgn::<P>(main_variation, self.starting_board()?, &mut movetext)?
+            });
+        }
+
+        movetext.push(char_to_result(self.result));
+        writeln!(writer, "{}", movetext.text)?;
+
+        return Ok(());
+    }
+
+    fn variation_to_pgn<P: CmbrPosition>(
+        &self,
+        variation: &CmbrVariation,
+        mut board: P,
+        movetext: &mut MovetextWriter,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut previous_board = board.clone();
+        let mut half_move = variation.starts_at;
+        let mut needs_move_number = true;
+        let mut comments = variation.comments.iter().peekable();
+        // The variations pushed since the last move
+        let mut variations = 0;
+
+        for cmbr in &variation.moves {
+            match SanToCmbrMvConvertor::cmbr_to_shakmaty_move(&board, *cmbr)? {
+                DecodedCmbrMv::VariationPointer(pointer) => {
+                    needs_move_number |=
+                        movetext.push_comments(&mut comments, half_move, variations);
+                    variations += 1;
+
+                    if let Some(sub_variation) = self.variations.get(&pointer) {
+                        movetext.push("(");
+                        self.variation_to_pgn(sub_variation, previous_board.clone(), movetext)?;
+                        movetext.push(")");
+
+                        needs_move_number = true;
+                    }
+                }
+
+                DecodedCmbrMv::Nag(nag) => movetext.push(&format!("${nag}")),
+
+                DecodedCmbrMv::Move {
+                    shakmaty_move, san, ..
+                } => {
+                    needs_move_number |= movetext.push_comments(&mut comments, half_move, u16::MAX);
+
+                    let san = san.to_string();
+
+                    // shakmaty writes pawn drops as `@e4`, but PGN readers expect `P@e4`
+                    if san.starts_with('@') {
+                        movetext.push_move(&format!("P{san}"), half_move, needs_move_number);
+                    } else {
+                        movetext.push_move(&san, half_move, needs_move_number);
+                    }
+
+                    previous_board = board.clone();
+                    board.play_unchecked(&shakmaty_move);
+                    half_move += 1;
+                    variations = 0;
+                    needs_move_number = false;
+                }
+
+                DecodedCmbrMv::NullMove => {
+                    needs_move_number |= movetext.push_comments(&mut comments, half_move, u16::MAX);
+                    movetext.push_move("--", half_move, needs_move_number);
+
+                    previous_board = board.clone();
+                    SanToCmbrMvConvertor::play_null_move(&mut board)?;
+                    half_move += 1;
+                    variations = 0;
+                    needs_move_number = false;
+                }
+            }
+        }
+
+        movetext.push_comments(&mut comments, half_move, u16::MAX);
+
+        return Ok(());
+    }
+}
@@ -1,15 +1,116 @@
+pub mod builder;
+pub mod cmbrmove;
+pub mod cmbrtopgn;
+pub mod packedposition;
 pub mod pgntocmbr;
+pub mod reader;
+pub mod replay;
 pub mod santocmbrmv;
+pub mod sections;
 pub mod structs;
 mod tests;
 mod u24_impl;
+pub mod writer;
 
+pub use builder::*;
+pub use cmbrmove::*;
+pub use packedposition::*;
+pub use reader::*;
+pub use replay::*;
 pub use santocmbrmv::*;
+pub use sections::*;
 pub use structs::*;
 pub use u24_impl::*;
+pub use writer::*;
+
+use crate::error::LibCmbrError;
+use memmap2::Mmap;
+
+/// The zstd compression level used by `CmbrFile::serialize`
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 9;
 
 impl CmbrFile {
+    /// Serializes the file. If `is_compressed` is set, the payload is compressed with
+    /// `DEFAULT_COMPRESSION_LEVEL`
     pub fn serialize(&self) -> Vec<u8> {
-        return bitcode::serialize(&self).unwrap();
+        return self.serialize_with_compression_level(DEFAULT_COMPRESSION_LEVEL);
+    }
+
+    /// Serializes the file. `compression_level` is the zstd level (1-22), and is ignored
+    /// if `is_compressed` isn't set
+    pub fn serialize_with_compression_level(&self, compression_level: i32) -> Vec<u8> {
+        let codec = CmbrSectionCodec::for_compression(self.is_compressed);
+        let sections = self.encode_sections(codec, compression_level);
+
+        let table_end = CMBR_HEADER_SIZE + sections.len() * CMBR_SECTION_ENTRY_SIZE;
+        let total_length = table_end + sections.iter().map(|(_, s)| s.len()).sum::<usize>();
+
+        let mut bytes = Vec::with_capacity(total_length);
+        bytes.extend_from_slice(CMBR_MAGIC_BYTES);
+        bytes.push(CMBR_FILE_FLAGS);
+        bytes.push(sections.len() as u8);
+
+        let mut offset = table_end as u64;
+        for (kind, section) in &sections {
+            let entry = CmbrSectionEntry {
+                kind: *kind,
+                codec,
+                offset,
+                length: section.len() as u64,
+            };
+
+            bytes.extend_from_slice(&entry.to_bytes());
+            offset += entry.length;
+        }
+
+        for (_, section) in &sections {
+            bytes.extend_from_slice(section);
+        }
+
+        return bytes;
+    }
+
+    /// Serializes every section of the file, and encodes it with `codec`
+    pub(crate) fn encode_sections(&self, codec: u8, compression_level: i32) -> Vec<(u8, Vec<u8>)> {
+        let mut sections = vec![
+            (
+                CmbrSectionKind::Headers,
+                bitcode::serialize(&self.headers_section()),
+            ),
+            (
+                CmbrSectionKind::Moves,
+                bitcode::serialize(&self.moves_section()),
+            ),
+            (
+                CmbrSectionKind::Comments,
+                bitcode::serialize(&self.comments_section()),
+            ),
+        ];
+
+        // Files converted without position tables don't have a positions section
+        if !self.encountered_positions.is_empty() {
+            sections.push((
+                CmbrSectionKind::Positions,
+                bitcode::serialize(&self.positions_section()),
+            ));
+        }
+
+        return sections
+            .into_iter()
+            .map(|(kind, bytes)| {
+                (
+                    kind,
+                    encode_section(bytes.unwrap(), codec, compression_level),
+                )
+            })
+            .collect();
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, LibCmbrError> {
+        return CmbrReader::new(bytes)?.read();
+    }
+
+    pub fn from_mmap(mmap: &Mmap) -> Result<Self, LibCmbrError> {
+        return CmbrReader::from_mmap(mmap)?.read();
     }
 }
@@ -0,0 +1,297 @@
+use crate::error::{LibCmbrError, LibCmbrErrorType};
+use crate::utils::def_enum;
+
+use shakmaty::fen::Fen;
+use shakmaty::{
+    Bitboard, Board, ByColor, ByRole, CastlingMode, Color, EnPassantMode, FromSetup, Piece,
+    Position, RemainingChecks, Role, Setup, Square,
+};
+use std::num::NonZeroU32;
+
+def_enum! (
+    #[doc = "Flags stored in the byte after the pieces of a `CmbrPackedPosition`. Every flag except `BlackToMove` means that its field follows, in the order of the flags"]
+    pub CmbrPackedPositionFlags => u8 {
+        BlackToMove     => 1 << 0,
+        CastlingCorners => 1 << 1, // 1 byte. Castling rights of the rooks on a1, h1, a8 and h8, in bits 0-3
+        CastlingRooks   => 1 << 2, // 8 bytes. A bitboard of the castling rooks, used if any of them isn't on a corner
+        EnPassant       => 1 << 3, // 1 byte. The en passant square
+        Pockets         => 1 << 4, // 12 bytes. The pockets of white and then black, by role from pawn to king
+        Promoted        => 1 << 5, // 8 bytes. A bitboard of the promoted pieces
+        RemainingChecks => 1 << 6, // 2 bytes. The remaining checks of white and then black
+});
+
+const CASTLING_CORNERS: [Square; 4] = [Square::A1, Square::H1, Square::A8, Square::H8];
+
+const ROLES: [Role; 6] = [
+    Role::Pawn,
+    Role::Knight,
+    Role::Bishop,
+    Role::Rook,
+    Role::Queen,
+    Role::King,
+];
+
+/// A position packed into bytes. The board is stored as a bitboard of the occupied squares
+/// (u64 LE), followed by a nibble for every occupied square in ascending order, 2 per byte with
+/// the lower one first. Nibbles are `CmbrMvPiece` values. The flags follow (See
+/// `CmbrPackedPositionFlags`). The halfmove clock and the fullmove number aren't stored.
+///
+/// Converting a `Setup` into a `CmbrPackedPosition` and back is lossless, apart from the
+/// counters, and equal positions are always packed into equal bytes
+#[cfg_attr(feature = "bitcode", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct CmbrPackedPosition(pub Vec<u8>);
+
+fn corrupted<T>() -> Result<T, LibCmbrError> {
+    return Err(LibCmbrError::new(LibCmbrErrorType::CorruptedFile));
+}
+
+/// Reads the bytes of a `CmbrPackedPosition` in order
+struct PackedReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> PackedReader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], LibCmbrError> {
+        if self.bytes.len() < count {
+            return corrupted();
+        }
+
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+
+        return Ok(taken);
+    }
+
+    fn u8(&mut self) -> Result<u8, LibCmbrError> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    fn bitboard(&mut self) -> Result<Bitboard, LibCmbrError> {
+        // SAFE: Safe. `take` returns exactly 8 bytes
+        let bytes = unsafe { self.take(8)?.try_into().unwrap_unchecked() };
+
+        return Ok(Bitboard(u64::from_le_bytes(bytes)));
+    }
+
+    fn square(&mut self) -> Result<Square, LibCmbrError> {
+        let square = self.u8()?;
+        if square >= 64 {
+            return corrupted();
+        }
+
+        return Ok(Square::new(square as u32));
+    }
+}
+
+impl CmbrPackedPosition {
+    pub fn from_setup(setup: &Setup) -> Self {
+        let occupied = setup.board.occupied();
+        let mut bytes = Vec::with_capacity(8 + 16 + 2);
+        bytes.extend_from_slice(&occupied.0.to_le_bytes());
+
+        let mut nibbles = occupied.into_iter().map(|square| {
+            // SAFE: Safe. `square` is occupied
+            let piece = unsafe { setup.board.piece_at(square).unwrap_unchecked() };
+
+            let color_bits = if piece.color == Color::Black {
+                0b1000
+            } else {
+                0
+            };
+
+            (piece.role as u8 - 1) | color_bits
+        });
+
+        while let Some(low) = nibbles.next() {
+            bytes.push(low | nibbles.next().unwrap_or(0) << 4);
+        }
+
+        let flags_i = bytes.len();
+        let mut flags = if setup.turn == Color::Black {
+            CmbrPackedPositionFlags::BlackToMove
+        } else {
+            0
+        };
+        bytes.push(0);
+
+        if setup.castling_rights.any() {
+            if (setup.castling_rights & !Bitboard::CORNERS).any() {
+                flags |= CmbrPackedPositionFlags::CastlingRooks;
+                bytes.extend_from_slice(&setup.castling_rights.0.to_le_bytes());
+            } else {
+                flags |= CmbrPackedPositionFlags::CastlingCorners;
+                bytes.push(
+                    CASTLING_CORNERS
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, corner)| setup.castling_rights.contains(**corner))
+                        .fold(0, |corners, (i, _)| corners | 1 << i),
+                );
+            }
+        }
+
+        if let Some(ep_square) = setup.ep_square {
+            flags |= CmbrPackedPositionFlags::EnPassant;
+            bytes.push(ep_square as u8);
+        }
+
+        if let Some(pockets) = &setup.pockets {
+            flags |= CmbrPackedPositionFlags::Pockets;
+
+            for color in [Color::White, Color::Black] {
+                for role in ROLES {
+                    bytes.push(*pockets.get(color).get(role));
+                }
+            }
+        }
+
+        if setup.promoted.any() {
+            flags |= CmbrPackedPositionFlags::Promoted;
+            bytes.extend_from_slice(&setup.promoted.0.to_le_bytes());
+        }
+
+        if let Some(remaining_checks) = &setup.remaining_checks {
+            flags |= CmbrPackedPositionFlags::RemainingChecks;
+
+            for color in [Color::White, Color::Black] {
+                bytes.push(u32::from(*remaining_checks.get(color)) as u8);
+            }
+        }
+
+        bytes[flags_i] = flags;
+
+        return Self(bytes);
+    }
+
+    /// Packs `board`. The en passant square is only kept if en passant is legal
+    pub fn from_position<P: Position + Clone>(board: &P) -> Self {
+        return Self::from_setup(&board.clone().into_setup(EnPassantMode::Legal));
+    }
+
+    pub fn from_fen(fen: &str) -> Result<Self, LibCmbrError> {
+        let fen: Fen = fen
+            .parse()
+            .map_err(|_| LibCmbrError::new(LibCmbrErrorType::InvalidFen))?;
+
+        return Ok(Self::from_setup(&Fen::into_setup(fen)));
+    }
+
+    /// Fails with `CorruptedFile` if the bytes aren't a packed position
+    pub fn to_setup(&self) -> Result<Setup, LibCmbrError> {
+        let mut reader = PackedReader { bytes: &self.0 };
+        let occupied = reader.bitboard()?;
+        let pieces = reader.take(occupied.count().div_ceil(2))?;
+
+        let mut board = Board::empty();
+        for (i, square) in occupied.into_iter().enumerate() {
+            let nibble = pieces[i / 2] >> (i % 2 * 4) & 0b1111;
+            let Some(role) = ROLES.get((nibble & 0b0111) as usize) else {
+                return corrupted();
+            };
+
+            let piece = Piece {
+                color: Color::from_white(nibble & 0b1000 == 0),
+                role: *role,
+            };
+            board.set_piece_at(square, piece);
+        }
+
+        // The unused nibble of an odd number of pieces is zero
+        if occupied.count() % 2 == 1 && pieces[pieces.len() - 1] >> 4 != 0 {
+            return corrupted();
+        }
+
+        let flags = reader.u8()?;
+        if flags >> 7 != 0 {
+            return corrupted();
+        }
+
+        let mut castling_rights = Bitboard::EMPTY;
+
+        if flags & CmbrPackedPositionFlags::CastlingCorners != 0 {
+            let corners = reader.u8()?;
+            if corners >> CASTLING_CORNERS.len() != 0 {
+                return corrupted();
+            }
+
+            for (i, corner) in CASTLING_CORNERS.iter().enumerate() {
+                if corners & 1 << i != 0 {
+                    castling_rights.add(*corner);
+                }
+            }
+        }
+
+        if flags & CmbrPackedPositionFlags::CastlingRooks != 0 {
+            castling_rights |= reader.bitboard()?;
+        }
+
+        let ep_square = if flags & CmbrPackedPositionFlags::EnPassant != 0 {
+            Some(reader.square()?)
+        } else {
+            None
+        };
+
+        let pockets = if flags & CmbrPackedPositionFlags::Pockets != 0 {
+            let mut pockets = ByColor::<ByRole<u8>>::default();
+
+            for color in [Color::White, Color::Black] {
+                for role in ROLES {
+                    *pockets.get_mut(color).get_mut(role) = reader.u8()?;
+                }
+            }
+
+            Some(pockets)
+        } else {
+            None
+        };
+
+        let promoted = if flags & CmbrPackedPositionFlags::Promoted != 0 {
+            reader.bitboard()?
+        } else {
+            Bitboard::EMPTY
+        };
+
+        let remaining_checks = if flags & CmbrPackedPositionFlags::RemainingChecks != 0 {
+            let mut remaining_checks = ByColor::<RemainingChecks>::default();
+
+            for color in [Color::White, Color::Black] {
+                let checks = reader.u8()?;
+                if checks > 3 {
+                    return corrupted();
+                }
+
+                *remaining_checks.get_mut(color) = RemainingChecks::new(checks as u32);
+            }
+
+            Some(remaining_checks)
+        } else {
+            None
+        };
+
+        if !reader.bytes.is_empty() {
+            return corrupted();
+        }
+
+        return Ok(Setup {
+            board,
+            promoted,
+            pockets,
+            turn: Color::from_white(flags & CmbrPackedPositionFlags::BlackToMove == 0),
+            castling_rights,
+            ep_square,
+            remaining_checks,
+            halfmoves: 0,
+            fullmoves: NonZeroU32::MIN,
+        });
+    }
+
+    /// Unpacks the position. `P` should match the variant of the game that reached it. Fails
+    /// with `CorruptedFile` if the position isn't legal in `P`
+    pub fn to_position<P: FromSetup + Position>(
+        &self,
+        castling_mode: CastlingMode,
+    ) -> Result<P, LibCmbrError> {
+        return P::from_setup(self.to_setup()?, castling_mode).or_else(|_| corrupted());
+    }
+}
@@ -1,5 +1,6 @@
-use super::{CmbrFile, SanToCmbrMvConvertor};
+use super::{estimated_file_size, insert_position, move_positions, position_hash, with_position_type, CmbrComment, CmbrCommentKind, CmbrConversionOptions, CmbrFen, CmbrFile, CmbrInvalidGamePolicy, CmbrMove, CmbrPackedPosition, CmbrPosition, CmbrVariant, CmbrWriter, MoveId, PositionKey, SanToCmbrMvConvertor};
 use crate::cmbr::CmbrGame;
+use crate::error::{LibCmbrDiagnostic, LibCmbrError, LibCmbrErrorType};
 use crate::cmbr::CmbrVariation;
 use crate::pgn::VariationPointerT;
 use crate::pgn::{PgnGame, PgnToken};
@@ -9,17 +10,18 @@ use pgn_lexer::parser::Token;
 use phf::phf_map;
 
 use shakmaty::fen::Fen;
-use shakmaty::zobrist::{Zobrist32, ZobristHash};
-use shakmaty::{CastlingSide, Chess, Color, Position};
+use shakmaty::{CastlingMode, Chess, Color, Position};
 
 use std::collections::HashMap;
 use std::error::Error;
 use std::io::Write;
 use std::str::from_utf8_unchecked;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+/// The half moves before a move number, or `None` if they don't fit in a u16
 macro_rules! move_to_halfmove {
     ($move:expr, $is_black:expr) => {
-        (2 * $move) - 1 - ($is_black == false) as u16
+        $move.checked_mul(2).and_then(|n| n.checked_sub(if $is_black { 1 } else { 2 }))
     };
 }
 
@@ -32,243 +34,701 @@ static MOVE_ANNOTATION_TO_NAG: phf::Map<&[u8], u8> = phf_map! {
     b"?!" => 6,
 };
 
-static RESULT_TO_CHAR: phf::Map<&[u8], char> = phf_map! {
+pub(crate) static RESULT_TO_CHAR: phf::Map<&[u8], char> = phf_map! {
     b"*" => 'u',
     b"1-0" => 'w',
     b"0-1" => 'b',
     b"1/2-1/2" => 'd',
 };
 
-fn get_fen_from_board(board: &Chess) -> String {
-    let mut fen = board.board().board_fen(board.promoted()).to_string();
-    fen.push_str(if board.turn() == Color::White {
-        " w "
-    } else {
-        " b "
-    });
+/// The amount of half moves played before `board`, according to its fullmove counter. Fails
+/// with `InvalidFen` if it doesn't fit in a `u16`
+pub(crate) fn halfmoves_before<P: Position>(board: &P) -> Result<u16, LibCmbrError> {
+    let halfmoves = (board.fullmoves().get() as u64 - 1) * 2 + (board.turn() == Color::Black) as u64;
 
-    let castles = board.castles();
+    return u16::try_from(halfmoves).map_err(|_| LibCmbrError::new(LibCmbrErrorType::InvalidFen));
+}
 
-    if castles.has(Color::White, CastlingSide::KingSide) {
-        fen.push('K')
-    };
+fn header<'a>(headers: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    return headers.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+}
 
-    if castles.has(Color::White, CastlingSide::QueenSide) {
-        fen.push('Q')
+/// Returns the variant of a game from its `Variant` header. See `CmbrVariant`
+pub(crate) fn variant_from_headers(headers: &[(String, String)]) -> u8 {
+    let variant = header(headers, "Variant").map(|v| v.to_ascii_lowercase().replace([' ', '-'], ""));
+
+    return match variant.as_deref() {
+        Some("chess960" | "fischerandom" | "fischerrandom") => CmbrVariant::Chess960,
+        Some("crazyhouse") => CmbrVariant::Crazyhouse,
+        Some("atomic") => CmbrVariant::Atomic,
+        Some("antichess" | "giveaway" | "suicide") => CmbrVariant::Antichess,
+        Some("kingofthehill" | "koth") => CmbrVariant::KingOfTheHill,
+        Some("threecheck" | "3check") => CmbrVariant::ThreeCheck,
+        Some("horde") => CmbrVariant::Horde,
+        Some("racingkings") => CmbrVariant::RacingKings,
+        _ => CmbrVariant::Standard,
     };
+}
 
-    if castles.has(Color::Black, CastlingSide::KingSide) {
-        fen.push('k')
+/// Returns the position a game starts from, and its FEN if it isn't the standard starting
+/// position. The `FEN` header is used unless `SetUp` is "0"
+pub(crate) fn starting_position<P: CmbrPosition>(
+    headers: &[(String, String)],
+    castling_mode: CastlingMode,
+) -> Result<(P, Option<CmbrFen>), Box<dyn Error>> {
+    let fen = match header(headers, "FEN") {
+        Some(fen) if header(headers, "SetUp") != Some("0") => fen,
+        _ => return Ok((P::default(), None)),
     };
 
-    if castles.has(Color::Black, CastlingSide::QueenSide) {
-        fen.push('q')
-    };
+    let board: P = fen
+        .trim()
+        .parse::<Fen>()?
+        .into_position(castling_mode)
+        .map_err(|_| LibCmbrError::new(LibCmbrErrorType::InvalidFen))?;
+    halfmoves_before(&board)?;
 
-    let ep_square = board.ep_square(shakmaty::EnPassantMode::Legal);
-    if ep_square.is_some() {
-        fen.push(' ');
-        // SAFE: Safe
-        fen.push_str(&unsafe { ep_square.unwrap_unchecked() }.to_string());
+    let fen = Fen::from_position(board.clone(), shakmaty::EnPassantMode::Legal).to_string();
+
+    return Ok((board, Some(fen)));
+}
+
+/// Returns the FEN of `board` without the halfmove clock and the fullmove number, for
+/// diagnostics. Pockets and promoted pieces are kept
+fn get_fen_from_board<P: CmbrPosition>(board: &P) -> String {
+    let fen = Fen::from_position(board.clone(), shakmaty::EnPassantMode::Legal).to_string();
+
+    // SAFE: Safe. `rsplitn` always yields at least one item
+    return unsafe { fen.rsplitn(3, ' ').last().unwrap_unchecked() }.to_owned();
+}
+
+fn insert_initial_position(positions: &mut HashMap<PositionKey, CmbrPackedPosition>) {
+    let board = Chess::new();
+
+    insert_position(positions, position_hash(&board), &CmbrPackedPosition::from_position(&board));
+}
+
+/// Prints the progress every 1000 games. `progress` is the amount of games converted so far
+fn report_progress(progress: &AtomicUsize, len: usize) {
+    let game_i = progress.fetch_add(1, Ordering::Relaxed);
+
+    if game_i % 1000 == 0 || game_i == len {
+        print!("{}\r", game_i as f64 / len as f64 * 100.0);
+        let _ = std::io::stdout().flush();
+    }
+}
+
+/// Prints the amount of games converted so far, every 1000 games
+fn report_game_count(previous_count: usize, count: usize) {
+    if previous_count / 1000 != count / 1000 {
+        print!("{count}\r");
+        let _ = std::io::stdout().flush();
     }
+}
+
+/// The amount of games every thread converts in the first batch of
+/// `CmbrWriter::convert_games_multithreaded`, before the size of a game is known
+const FIRST_BATCH_GAMES_PER_THREAD: usize = 64;
+
+/// A convertor for each of `thread_count` threads, which split `table_memory_limit` evenly
+fn thread_convertors(table_memory_limit: u64, thread_count: usize) -> Vec<SanToCmbrMvConvertor> {
+    let thread_count = thread_count.max(1);
+
+    // A limit of 0 means the default one, so every share is at least a byte
+    let memory_limit = if table_memory_limit == 0 {
+        0
+    } else {
+        (table_memory_limit / thread_count as u64).max(1)
+    };
+
+    return (0..thread_count)
+        .map(|_| SanToCmbrMvConvertor::new(memory_limit))
+        .collect();
+}
 
-    return fen;
+/// Whether the move before `tokens` has variations that are alternatives to it, which start
+/// from the position before it
+fn has_alternatives(tokens: &[PgnToken]) -> bool {
+    return tokens
+        .iter()
+        .take_while(|token| !matches!(token, PgnToken::Token(Token::Move(_) | Token::NullMove(_))))
+        .any(|token| matches!(token, PgnToken::VariationPointer(_)));
+}
+
+/// What `from_ast` and `from_ast_multithreaded` do with diagnostics
+fn print_diagnostic(diagnostic: LibCmbrDiagnostic) {
+    eprintln!("[WARN] {diagnostic}");
 }
 
 impl CmbrFile {
     // TODO(#22): Write tests for CmbrFile::from_ast
-    // TODO(#28): Split the Headers, Moves and the encountered positions into seperate files and compress them seperatly
-    // TODO(#29): Reduce the memory footprint of the program
-    // Currently the program uses 33x amount of memory as the input file.
+    /// Converts every game in memory. `CmbrWriter::convert_games` converts games that don't
+    /// fit into memory
     pub fn from_ast(
         ast: Vec<PgnGame>,
         convertor: &mut SanToCmbrMvConvertor,
         is_compressed: bool,
     ) -> Result<Self, Box<dyn Error>> {
-        debug_assert!(!is_compressed);
+        return Self::from_ast_with_diagnostics(
+            ast,
+            convertor,
+            CmbrConversionOptions { is_compressed, ..Default::default() },
+            print_diagnostic,
+        );
+    }
 
-        let mut file = CmbrFile::new(is_compressed);
-        let mut board = Chess::new();
+    /// Same as `from_ast`, but every error encountered in a game is passed to `on_diagnostic`
+    /// instead of being printed to stderr, and games that can't be fully converted are handled
+    /// according to `options.policy`. With `CmbrInvalidGamePolicy::Abort` the error is returned
+    /// instead. The position tables are left empty unless `options.with_positions` is set
+    pub fn from_ast_with_diagnostics(
+        ast: Vec<PgnGame>,
+        convertor: &mut SanToCmbrMvConvertor,
+        options: CmbrConversionOptions,
+        mut on_diagnostic: impl FnMut(LibCmbrDiagnostic),
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut file = CmbrFile::new(options.is_compressed);
+        if options.with_positions {
+            insert_initial_position(&mut file.encountered_positions);
+        }
+
+        let progress = AtomicUsize::new(0);
+
+        for (game_i, game) in ast.iter().enumerate() {
+            report_progress(&progress, ast.len());
+
+            let mut diagnostics = Vec::new();
+            let cmbr_game = Self::convert_game(
+                game_i,
+                game,
+                convertor,
+                options,
+                &mut file.encountered_positions,
+                &mut diagnostics,
+            );
+
+            diagnostics.into_iter().for_each(&mut on_diagnostic);
+
+            if let Some(cmbr_game) = cmbr_game? {
+                file.games.insert(file.games.len() as u32, cmbr_game);
+            }
+        }
 
-        let fen = get_fen_from_board(&board);
+        Ok(file)
+    }
 
-        let _ = file.encountered_positions.try_insert(
-            board
-                .zobrist_hash::<Zobrist32>(shakmaty::EnPassantMode::Legal)
-                .0,
-            fen,
+    /// Same as `from_ast`, but the games are split between `thread_count` threads. Every
+    /// thread gets its own `SanToCmbrMvConvertor`, and `table_memory_limit` is split evenly
+    /// between them. The result is the same as the one of `from_ast`
+    pub fn from_ast_multithreaded(
+        ast: Vec<PgnGame>,
+        table_memory_limit: u64,
+        is_compressed: bool,
+        thread_count: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        return Self::from_ast_multithreaded_with_diagnostics(
+            ast,
+            table_memory_limit,
+            thread_count,
+            CmbrConversionOptions { is_compressed, ..Default::default() },
+            print_diagnostic,
         );
+    }
 
-        let len = ast.len();
+    /// Same as `from_ast_multithreaded`, but diagnostics and invalid games are handled like in
+    /// `from_ast_with_diagnostics`. Diagnostics are passed once every thread is done, in the order
+    /// of the games
+    pub fn from_ast_multithreaded_with_diagnostics(
+        ast: Vec<PgnGame>,
+        table_memory_limit: u64,
+        thread_count: usize,
+        options: CmbrConversionOptions,
+        on_diagnostic: impl FnMut(LibCmbrDiagnostic),
+    ) -> Result<Self, Box<dyn Error>> {
+        return Self::convert_multithreaded(
+            &ast,
+            0,
+            &mut thread_convertors(table_memory_limit, thread_count),
+            options,
+            on_diagnostic,
+            true,
+        );
+    }
 
-        (0..len).for_each(|game_i| {
-            if game_i % 1000 == 0 || game_i == len {
-                print!("{}\r", game_i as f64 / len as f64 * 100.0);
-                let _ = std::io::stdout().flush();
-            }
+    /// The games of `ast` are numbered from `first_game_i` in diagnostics. Every thread uses one
+    /// of `convertors`, so there are as many threads as convertors
+    fn convert_multithreaded(
+        ast: &[PgnGame],
+        first_game_i: usize,
+        convertors: &mut [SanToCmbrMvConvertor],
+        options: CmbrConversionOptions,
+        mut on_diagnostic: impl FnMut(LibCmbrDiagnostic),
+        show_progress: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        let thread_count = convertors.len().clamp(1, ast.len().max(1));
+        let chunk_size = ast.len().div_ceil(thread_count).max(1);
+        let progress = AtomicUsize::new(0);
+
+        let chunks = std::thread::scope(|scope| {
+            let handles: Vec<_> = ast
+                .chunks(chunk_size)
+                .zip(convertors.iter_mut())
+                .enumerate()
+                .map(|(chunk_i, (chunk, convertor))| {
+                    let progress = &progress;
+                    let len = ast.len();
+
+                    scope.spawn(move || {
+                        let mut positions = HashMap::with_capacity(1024);
+                        let mut diagnostics = Vec::new();
+                        if options.with_positions {
+                            insert_initial_position(&mut positions);
+                        }
 
-            file.games.insert(game_i as u32, CmbrGame::new());
+                        let mut games = Vec::with_capacity(chunk.len());
 
-            // SAFE: Safe
-            let cmbr_game = unsafe { file.games.get_mut(&(game_i as u32)).unwrap_unchecked() };
-            let game = &ast[game_i];
+                        for (i, game) in chunk.iter().enumerate() {
+                            if show_progress {
+                                report_progress(progress, len);
+                            }
 
-            // TODO(#30): Support fen headers in libcmbr
-            board = Chess::new();
+                            let cmbr_game = Self::convert_game(
+                                first_game_i + chunk_i * chunk_size + i,
+                                game,
+                                convertor,
+                                options,
+                                &mut positions,
+                                &mut diagnostics,
+                            );
+
+                            match cmbr_game {
+                                Ok(Some(cmbr_game)) => games.push(cmbr_game),
+                                Ok(None) => {}
+                                Err(error) => return (games, positions, diagnostics, Some(error)),
+                            }
+                        }
 
-            let _ = cmbr_game.encountered_positions.try_insert(0, board
-                .zobrist_hash::<Zobrist32>(shakmaty::EnPassantMode::Legal)
-                .0);
+                        (games, positions, diagnostics, None)
+                    })
+                })
+                .collect();
 
-            {
-                let mut current_key: &[u8] = &[];
+            return handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>();
+        });
 
-                for header in &game.global_tokens {
-                    match header {
-                        Token::Result(r) => cmbr_game.result = RESULT_TO_CHAR[r],
-                        Token::TagSymbol(k) => current_key = k,
+        let mut file = CmbrFile::new(options.is_compressed);
 
-                        // SAFE: Safe
-                        Token::TagString(v) => unsafe {
-                            cmbr_game.headers.push((
-                                from_utf8_unchecked(current_key).to_owned(),
-                                from_utf8_unchecked(v).to_owned(),
-                            ));
-                        },
+        // Chunks are merged in order, so the first game that reached a position wins, like in `from_ast`
+        for (games, positions, diagnostics, error) in chunks {
+            diagnostics.into_iter().for_each(&mut on_diagnostic);
 
-                        _ => {}
-                    }
-                }
+            if let Some(error) = error {
+                return Err(Box::new(error));
             }
 
-            let variations = &game.variations;
-            let variations_iter = variations.iter();
+            for mut game in games {
+                move_positions(&mut game.encountered_positions, &positions, &mut file.encountered_positions);
+                file.games.insert(file.games.len() as u32, game);
+            }
+        }
 
-            let mut variation_pointers: HashMap<VariationPointerT, VariationPointerT> = HashMap::with_capacity(1);
-            variation_pointers.insert(0, 0);
+        Ok(file)
+    }
 
-            for (id, variation) in variations_iter {
-                if variation.0.is_empty() {
-                    eprintln!("[WARN] Empty variation on game N{game_i}. Skipping game");
-                    break;
+    /// Converts a game, and adds the positions it reached to `positions` unless the game is left
+    /// out according to `options.policy`. Returns `None` if the game is left out
+    fn convert_game(
+        game_i: usize,
+        game: &PgnGame,
+        convertor: &mut SanToCmbrMvConvertor,
+        options: CmbrConversionOptions,
+        positions: &mut HashMap<PositionKey, CmbrPackedPosition>,
+        diagnostics: &mut Vec<LibCmbrDiagnostic>,
+    ) -> Result<Option<CmbrGame>, LibCmbrDiagnostic> {
+        // Positions are only added to `positions` once the game is kept, so skipped games leave no trace
+        let mut game_positions = HashMap::new();
+        let (mut cmbr_game, error) = Self::game_from_ast(game_i, game, convertor, options.with_positions, &mut game_positions, diagnostics);
+
+        if let Some(error) = error {
+            match options.policy {
+                CmbrInvalidGamePolicy::Abort => return Err(error),
+                CmbrInvalidGamePolicy::Skip => {
+                    diagnostics.push(error);
+                    return Ok(None);
                 }
+                CmbrInvalidGamePolicy::KeepTruncated => diagnostics.push(error),
+            }
+        }
 
-                // SAFE: Safe. Empty variations shouldn't get to this point
-                let start_at =
-                    if let PgnToken::Token(Token::MoveNumber(number, is_black)) = variation.0[0] {
-                        move_to_halfmove!(number, is_black)
-                    } else {
-                        0
-                    };
+        move_positions(&mut cmbr_game.encountered_positions, &game_positions, positions);
 
-                let variation_pointer = *variation_pointers.get(id).unwrap() as u32;
-                let positions_pointer = (variation_pointer << 16) | start_at as u32;
+        return Ok(Some(cmbr_game));
+    }
 
-                let zobrist_hash = cmbr_game.encountered_positions.get(&positions_pointer);
-                if zobrist_hash.is_none() {
-                    eprintln!("[WARN] Skipping game: {game_i}");
-                    break;
+    /// Converts a game. The error that stopped the conversion of the game, if any, is returned
+    /// along with the partially converted game
+    fn game_from_ast(
+        game_i: usize,
+        game: &PgnGame,
+        convertor: &mut SanToCmbrMvConvertor,
+        with_positions: bool,
+        positions: &mut HashMap<PositionKey, CmbrPackedPosition>,
+        diagnostics: &mut Vec<LibCmbrDiagnostic>,
+    ) -> (CmbrGame, Option<LibCmbrDiagnostic>) {
+        let mut cmbr_game = CmbrGame::new();
+
+        {
+            let mut current_key: &[u8] = &[];
+
+            for header in &game.global_tokens {
+                match header {
+                    Token::Result(r) => match RESULT_TO_CHAR.get(r) {
+                        Some(result) => cmbr_game.result = *result,
+                        None => diagnostics.push(
+                            LibCmbrDiagnostic::new(LibCmbrErrorType::UnknownResult, game_i, 0)
+                                .with_token(String::from_utf8_lossy(r)),
+                        ),
+                    },
+                    Token::TagSymbol(k) => current_key = k,
+
+                    // SAFE: Safe
+                    Token::TagString(v) => unsafe {
+                        cmbr_game.headers.push((
+                            from_utf8_unchecked(current_key).to_owned(),
+                            from_utf8_unchecked(v).to_owned(),
+                        ));
+                    },
+
+                    // SAFE: Safe
+                    Token::EscapeComment(c) => cmbr_game
+                        .escape_comments
+                        .push(unsafe { from_utf8_unchecked(c) }.to_owned()),
+
+                    _ => {}
                 }
+            }
+        }
+
+        cmbr_game.variant = variant_from_headers(&cmbr_game.headers);
+
+        let result = with_position_type!(cmbr_game.variant, P => {
+            Self::variations_from_ast::<P>(game_i, game, convertor, with_positions, positions, diagnostics, &mut cmbr_game)
+        });
+
+        return (cmbr_game, result.err());
+    }
+
+    /// Variations start from the board their parent reached, so `positions` is only written to,
+    /// and only if `with_positions` is set
+    fn variations_from_ast<P: CmbrPosition>(
+        game_i: usize,
+        game: &PgnGame,
+        convertor: &mut SanToCmbrMvConvertor,
+        with_positions: bool,
+        positions: &mut HashMap<PositionKey, CmbrPackedPosition>,
+        diagnostics: &mut Vec<LibCmbrDiagnostic>,
+        cmbr_game: &mut CmbrGame,
+    ) -> Result<(), LibCmbrDiagnostic> {
+        let board = starting_position::<P>(&cmbr_game.headers, cmbr_game.castling_mode());
+        if board.is_err() {
+            let mut diagnostic = LibCmbrDiagnostic::new(LibCmbrErrorType::InvalidFen, game_i, 0);
+            if let Some(fen) = header(&cmbr_game.headers, "FEN") {
+                diagnostic = diagnostic.with_fen(fen);
+            }
+
+            return Err(diagnostic);
+        }
+
+        // SAFE: Safe
+        let (mut board, starting_fen) = unsafe { board.unwrap_unchecked() };
+        cmbr_game.starting_position = starting_fen;
+
+        // SAFE: Safe. `starting_position` checks it
+        let start_ply = unsafe { halfmoves_before(&board).unwrap_unchecked() };
+        if with_positions {
+            let key = insert_position(positions, position_hash(&board), &CmbrPackedPosition::from_position(&board));
+            let _ = cmbr_game.encountered_positions.try_insert(start_ply as u32, key);
+        }
+
+        // The boards variations start from, keyed like `CmbrGame::encountered_positions`
+        let mut boards: HashMap<MoveId, P> = HashMap::new();
+        boards.insert(start_ply as u32, board.clone());
 
-                // SAFE: Safe
-                let zobrist_hash = unsafe { zobrist_hash.unwrap_unchecked() };
-                let fen = file.encountered_positions.get(zobrist_hash).unwrap();
-                // SAFE: Safe
-                let fen: Fen = fen.parse().unwrap();
+        let variations = &game.variations;
+        let variations_iter = variations.iter();
 
-                board = fen.into_position(shakmaty::CastlingMode::Standard).unwrap();
+        let mut variation_pointers: HashMap<VariationPointerT, VariationPointerT> = HashMap::with_capacity(1);
+        variation_pointers.insert(0, 0);
 
-                let cmbr_variation = CmbrVariation::new(start_at);
-                cmbr_game.variations.insert(*id, cmbr_variation);
+        for (id, variation) in variations_iter {
+            if variation.0.is_empty() {
+                return Err(LibCmbrDiagnostic::new(LibCmbrErrorType::EmptyVariation, game_i, start_ply));
+            }
+
+            // SAFE: Safe. Empty variations shouldn't get to this point
+            let start_at =
+                if let PgnToken::Token(Token::MoveNumber(number, is_black)) = variation.0[0] {
+                    let Some(start_at) = move_to_halfmove!(number, is_black) else {
+                        return Err(
+                            LibCmbrDiagnostic::new(LibCmbrErrorType::InvalidMoveNumber, game_i, start_ply)
+                                .with_token(number.to_string()),
+                        );
+                    };
+
+                    start_at
+                } else {
+                    start_ply
+                };
+
+            // The main variation has to start from the starting position
+            if *id == 0 && start_at != start_ply {
+                return Err(
+                    LibCmbrDiagnostic::new(LibCmbrErrorType::InvalidMoveNumber, game_i, start_ply)
+                        .with_fen(get_fen_from_board(&board)),
+                );
+            }
+
+            let variation_pointer = *variation_pointers.get(id).unwrap() as u32;
+            let positions_pointer = (variation_pointer << 16) | start_at as u32;
+
+            let Some(variation_board) = boards.get(&positions_pointer) else {
+                return Err(LibCmbrDiagnostic::new(LibCmbrErrorType::MissingPosition, game_i, start_at));
+            };
+
+            board = variation_board.clone();
 
-                let cmbr_variation = cmbr_game.variations.get_mut(id).unwrap();
-                let mut current_move_number = start_at;
-                let mut skip_game = false;
+            let variation_start = (*id << 16) | start_at as u32;
+            if let Some(position_key) = cmbr_game.encountered_positions.get(&positions_pointer).copied() {
+                let _ = cmbr_game.encountered_positions.try_insert(variation_start, position_key);
+            }
 
-                for token in &variation.0 {
-                    if let PgnToken::VariationPointer(p) = token {
-                        cmbr_variation
-                            .moves
-                            .push((((*p as u32) << 8) | 0b10000000).into());
+            let cmbr_variation = CmbrVariation::new(start_at);
+            cmbr_game.variations.insert(*id, cmbr_variation);
 
-                        variation_pointers.insert(*p, *id);
+            let cmbr_variation = cmbr_game.variations.get_mut(id).unwrap();
+            let mut current_move_number = start_at;
+            // The variations after the last move, which comments after them come after
+            let mut variations_before: u16 = 0;
 
-                        continue;
+            for (token_i, token) in variation.0.iter().enumerate() {
+                if let PgnToken::VariationPointer(p) = token {
+                    // Pointers are stored in 16 bits of a CMBR-MV
+                    if *p > u16::MAX as VariationPointerT {
+                        return Err(LibCmbrDiagnostic::new(LibCmbrErrorType::TooManyVariations, game_i, current_move_number));
                     }
 
+                    cmbr_variation
+                        .moves
+                        .push(CmbrMove::VariationPointer(*p as u16).into());
 
-                    if let PgnToken::Token(t) = token {
-                        match t {
-                            Token::NAG(n) => {
-                                let mut nag_numeral =
-                                    // SAFE: Safe
-                                    (unsafe { from_utf8_unchecked(n) }).parse::<u32>().unwrap();
+                    variation_pointers.insert(*p, *id);
+                    variations_before += 1;
 
-                                nag_numeral <<= 8;
-                                nag_numeral |= 0b00001000;
+                    continue;
+                }
 
-                                cmbr_variation.moves.push(nag_numeral.into());
-                            }
 
-                            Token::Move(m) => {
-                                // TODO(#23): Handle errors in CmbrFile::from_ast
-                                let cmbrmv = convertor
-                                    .san_to_cmbr(&mut board, m);
-
-                                if cmbrmv.is_err() {
-                                    // TODO(#24): Skip game instead of not finishing convertion if invalid san occurs
-                                    eprintln!("[WARN] Not finishing convertion of N{game_i} due to invalid san. SAN: {} | Fen: {}",
-                                        std::str::from_utf8(m).unwrap(),
-                                        get_fen_from_board(&board));
-                                    skip_game = true;
-                                    break;
-                                }
-
-                                // SAFE: Safe
-                                let cmbrmv = unsafe { cmbrmv.unwrap_unchecked() };
-                                cmbr_variation.moves.push(cmbrmv);
-
-                                let hash = board
-                                    .zobrist_hash::<Zobrist32>(shakmaty::EnPassantMode::Legal)
-                                    .0;
-
-                                let fen = get_fen_from_board(&board);
-                                let _ = file.encountered_positions.try_insert(hash, fen);
-
-                                current_move_number += 1;
-                                let _ = cmbr_game.encountered_positions.insert(((*id as u32) << 16) | current_move_number as u32, hash);
+                if let PgnToken::LineComment(c) = token {
+                    cmbr_variation.comments.push(CmbrComment {
+                        half_move: current_move_number,
+                        kind: CmbrCommentKind::Line,
+                        variations_before,
+                        // SAFE: Safe
+                        text: unsafe { from_utf8_unchecked(c) }.to_owned(),
+                    });
+
+                    continue;
+                }
+
+                if let PgnToken::Token(t) = token {
+                    match t {
+                        Token::NAG(n) => {
+                            // SAFE: Safe
+                            let nag_numeral = (unsafe { from_utf8_unchecked(n) }).parse::<u8>();
+
+                            let Ok(nag_numeral) = nag_numeral else {
+                                diagnostics.push(
+                                    LibCmbrDiagnostic::new(LibCmbrErrorType::InvalidNag, game_i, current_move_number)
+                                        .with_fen(get_fen_from_board(&board))
+                                        .with_token(String::from_utf8_lossy(n)),
+                                );
+                                continue;
+                            };
+
+                            cmbr_variation.moves.push(CmbrMove::Nag(nag_numeral).into());
+                        }
+
+                        // Null moves (`--` or `Z0`) are converted by `san_to_cmbr` too
+                        Token::Move(m) | Token::NullMove(m) => {
+                            // Only the boards that variations start from are kept
+                            if has_alternatives(&variation.0[token_i + 1..]) {
+                                boards.insert((*id << 16) | current_move_number as u32, board.clone());
                             }
 
-                            Token::MoveAnnotation(an) => cmbr_variation.moves.push(
-                                (((MOVE_ANNOTATION_TO_NAG[*an] as u32) << 8) | 0b10000000)
-                                    .into(),
-                            ),
+                            let cmbrmv = convertor
+                                .san_to_cmbr(&mut board, m);
 
-                            Token::MoveNumber(number, is_black) => {
-                                current_move_number = move_to_halfmove!(number, *is_black);
+                            if cmbrmv.is_err() {
+                                return Err(
+                                    LibCmbrDiagnostic::new(LibCmbrErrorType::InvalidSan, game_i, current_move_number)
+                                        .with_fen(get_fen_from_board(&board))
+                                        .with_token(String::from_utf8_lossy(m)),
+                                );
                             }
 
-                            Token::Commentary(c) => {
-                                cmbr_variation
-                                    .comments
-                                    .push((
-                                        current_move_number,
-                                        // SAFE: Safe
-                                        unsafe { from_utf8_unchecked(c) }.to_owned(),
-                                    ));
+                            // SAFE: Safe
+                            let cmbrmv = unsafe { cmbrmv.unwrap_unchecked() };
+                            cmbr_variation.moves.push(cmbrmv);
+
+                            current_move_number += 1;
+                            variations_before = 0;
+                            let move_id = (*id << 16) | current_move_number as u32;
+
+                            if with_positions {
+                                let key = insert_position(positions, position_hash(&board), &CmbrPackedPosition::from_position(&board));
+                                let _ = cmbr_game.encountered_positions.insert(move_id, key);
                             }
+                        }
+
+                        // Move suffix annotations are stored as the NAGs they stand for
+                        Token::MoveAnnotation(an) => match MOVE_ANNOTATION_TO_NAG.get(an) {
+                            Some(nag) => cmbr_variation.moves.push(CmbrMove::Nag(*nag).into()),
+                            None => diagnostics.push(
+                                LibCmbrDiagnostic::new(LibCmbrErrorType::InvalidNag, game_i, current_move_number)
+                                    .with_fen(get_fen_from_board(&board))
+                                    .with_token(String::from_utf8_lossy(an)),
+                            ),
+                        },
+
+                        Token::MoveNumber(number, is_black) => {
+                            let Some(half_move) = move_to_halfmove!(number, *is_black) else {
+                                return Err(
+                                    LibCmbrDiagnostic::new(LibCmbrErrorType::InvalidMoveNumber, game_i, current_move_number)
+                                        .with_token(number.to_string()),
+                                );
+                            };
+
+                            current_move_number = half_move;
+                        }
 
-                            _ => {}
+                        Token::Commentary(c) | Token::EscapeComment(c) => {
+                            let kind = if let Token::EscapeComment(_) = t {
+                                CmbrCommentKind::Escape
+                            } else {
+                                CmbrCommentKind::Brace
+                            };
+
+                            cmbr_variation
+                                .comments
+                                .push(CmbrComment {
+                                    half_move: current_move_number,
+                                    kind,
+                                    variations_before,
+                                    // SAFE: Safe
+                                    text: unsafe { from_utf8_unchecked(c) }.to_owned(),
+                                });
                         }
+
+                        _ => {}
                     }
                 }
+            }
+        }
 
-                if skip_game {
-                    break;
-                }
+        return Ok(());
+    }
+}
+
+impl<W: Write> CmbrWriter<W> {
+    /// Converts `games` one by one and writes them, so only the game that is being converted and
+    /// the buffered games are kept in memory. Diagnostics and invalid games are handled like in
+    /// `CmbrFile::from_ast_with_diagnostics`
+    pub fn convert_games<'a>(
+        &mut self,
+        games: impl Iterator<Item = PgnGame<'a>>,
+        convertor: &mut SanToCmbrMvConvertor,
+        options: CmbrConversionOptions,
+        mut on_diagnostic: impl FnMut(LibCmbrDiagnostic),
+    ) -> Result<(), Box<dyn Error>> {
+        let mut positions = HashMap::new();
+
+        for (game_i, game) in games.enumerate() {
+            let mut diagnostics = Vec::new();
+            let cmbr_game = CmbrFile::convert_game(
+                game_i,
+                &game,
+                convertor,
+                options,
+                &mut positions,
+                &mut diagnostics,
+            );
+
+            diagnostics.into_iter().for_each(&mut on_diagnostic);
+
+            if let Some(cmbr_game) = cmbr_game? {
+                self.push_game(cmbr_game, &positions)?;
             }
-        });
 
-        Ok(file)
+            positions.clear();
+            report_game_count(game_i, game_i + 1);
+        }
+
+        Ok(())
+    }
+
+    /// Same as `convert_games`, but batches of games are converted on `thread_count` threads like
+    /// in `CmbrFile::from_ast_multithreaded_with_diagnostics`. Every thread keeps its own
+    /// `SanToCmbrMvConvertor` for the whole conversion, and `table_memory_limit` is split evenly
+    /// between them. A batch is converted in memory, so it's sized to fill the rest of the
+    /// writer's memory limit, based on the size of the games converted so far
+    pub fn convert_games_multithreaded<'a>(
+        &mut self,
+        mut games: impl Iterator<Item = PgnGame<'a>>,
+        table_memory_limit: u64,
+        thread_count: usize,
+        options: CmbrConversionOptions,
+        mut on_diagnostic: impl FnMut(LibCmbrDiagnostic),
+    ) -> Result<(), Box<dyn Error>> {
+        let thread_count = thread_count.max(1);
+        let mut game_count = 0;
+
+        // The estimated size of the games converted so far, and how many there are
+        let mut converted_size: u64 = 0;
+        let mut converted_games: u64 = 0;
+
+        let mut convertors = thread_convertors(table_memory_limit, thread_count);
+
+        loop {
+            let batch_size = match converted_size.checked_div(converted_games) {
+                Some(game_size) => ((self.remaining_memory() / game_size.max(1)) as usize).max(thread_count),
+                None => thread_count * FIRST_BATCH_GAMES_PER_THREAD,
+            };
+
+            let batch: Vec<PgnGame> = games.by_ref().take(batch_size).collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let file = CmbrFile::convert_multithreaded(
+                &batch,
+                game_count,
+                &mut convertors,
+                options,
+                &mut on_diagnostic,
+                false,
+            )?;
+
+            converted_size += estimated_file_size(&file);
+            converted_games += file.games.len() as u64;
+            self.push_file(file)?;
+
+            report_game_count(game_count, game_count + batch.len());
+            game_count += batch.len();
+        }
+
+        Ok(())
     }
 }
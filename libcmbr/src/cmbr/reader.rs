@@ -0,0 +1,305 @@
+use super::sections::*;
+use super::{move_positions, CmbrFile};
+use crate::error::{LibCmbrError, LibCmbrErrorType};
+use crate::utils::def_enum;
+
+use litemap::LiteMap;
+use memmap2::Mmap;
+
+/// Every CMBR file starts with these bytes
+pub const CMBR_MAGIC_BYTES: &[u8; 5] = b"CMBR!";
+/// Magic bytes, the flags byte, and the number of sections. The section table follows
+pub const CMBR_HEADER_SIZE: usize = CMBR_MAGIC_BYTES.len() + 2;
+
+def_enum! (
+    #[doc = "Flags stored in the header of a CMBR file"]
+    pub CmbrFileFlags => u8 {
+        // The section table is at the end of the file, and sections can be repeated. See `CmbrWriter`
+        Streamed => 1 << 0,
+        // Positions are keyed by 64 bit keys (See `PositionKey`)
+        WidePositionKeys => 1 << 1,
+        // Positions are stored as `CmbrPackedPosition`s
+        PackedPositions => 1 << 2,
+        // Comments have a kind (See `CmbrCommentKind`), and games can have escape comments
+        CommentKinds => 1 << 3,
+});
+
+/// Files with any other flag set are rejected
+const CMBR_KNOWN_FLAGS: u8 = CmbrFileFlags::Streamed | CMBR_FILE_FLAGS;
+
+/// The flags that decide how the positions section is laid out
+const CMBR_POSITION_FLAGS: u8 = CmbrFileFlags::WidePositionKeys | CmbrFileFlags::PackedPositions;
+
+/// The flags of every file written by this version, apart from `Streamed`. Files without any of
+/// them are rejected
+pub(crate) const CMBR_FILE_FLAGS: u8 = CMBR_POSITION_FLAGS | CmbrFileFlags::CommentKinds;
+
+/// A validated view over the bytes of a CMBR file
+#[derive(Debug, Clone, Copy)]
+pub struct CmbrReader<'a> {
+    bytes: &'a [u8],
+    flags: u8,
+    /// The section table. Sections of unknown kinds are ignored
+    table: &'a [u8],
+}
+
+impl<'a> CmbrReader<'a> {
+    /// Validates the header and the section table of `bytes`, and makes sure that every
+    /// section is inside of the file
+    pub fn new(bytes: &'a [u8]) -> Result<Self, LibCmbrError> {
+        if bytes.len() < CMBR_MAGIC_BYTES.len() {
+            return Err(if CMBR_MAGIC_BYTES.starts_with(bytes) {
+                LibCmbrError::new(LibCmbrErrorType::TruncatedFile)
+            } else {
+                LibCmbrError::new(LibCmbrErrorType::InvalidMagicBytes)
+            });
+        }
+
+        if &bytes[..CMBR_MAGIC_BYTES.len()] != CMBR_MAGIC_BYTES {
+            return Err(LibCmbrError::new(LibCmbrErrorType::InvalidMagicBytes));
+        }
+
+        if bytes.len() < CMBR_HEADER_SIZE {
+            return Err(LibCmbrError::new(LibCmbrErrorType::TruncatedFile));
+        }
+
+        let flags = bytes[CMBR_MAGIC_BYTES.len()];
+        if flags & !CMBR_KNOWN_FLAGS != 0 || flags & CMBR_FILE_FLAGS != CMBR_FILE_FLAGS {
+            return Err(LibCmbrError::new(LibCmbrErrorType::CorruptedFile));
+        }
+
+        let is_streamed = flags & CmbrFileFlags::Streamed != 0;
+
+        // Sections are between `sections_start` and `sections_end`
+        let (table, sections_start, sections_end) = if is_streamed {
+            // The section table is followed by the number of its entries (u64)
+            let count_start = bytes
+                .len()
+                .checked_sub(std::mem::size_of::<u64>())
+                .filter(|start| *start >= CMBR_HEADER_SIZE)
+                .ok_or(LibCmbrError::new(LibCmbrErrorType::TruncatedFile))?;
+
+            // SAFE: Safe. The slice is exactly 8 bytes long
+            let section_count =
+                u64::from_le_bytes(unsafe { bytes[count_start..].try_into().unwrap_unchecked() });
+
+            let table_start = section_count
+                .checked_mul(CMBR_SECTION_ENTRY_SIZE as u64)
+                .and_then(|size| (count_start as u64).checked_sub(size))
+                .filter(|start| *start >= CMBR_HEADER_SIZE as u64)
+                .ok_or(LibCmbrError::new(LibCmbrErrorType::CorruptedFile))?
+                as usize;
+
+            (
+                &bytes[table_start..count_start],
+                CMBR_HEADER_SIZE,
+                table_start,
+            )
+        } else {
+            let section_count = bytes[CMBR_MAGIC_BYTES.len() + 1] as usize;
+            let table_end = CMBR_HEADER_SIZE + section_count * CMBR_SECTION_ENTRY_SIZE;
+
+            if bytes.len() < table_end {
+                return Err(LibCmbrError::new(LibCmbrErrorType::TruncatedFile));
+            }
+
+            (&bytes[CMBR_HEADER_SIZE..table_end], table_end, bytes.len())
+        };
+
+        let reader = Self {
+            bytes,
+            flags,
+            table,
+        };
+
+        let mut seen_kinds = [false; CmbrSectionKind::VARIANTS.len()];
+        let mut file_end = sections_start as u64;
+        let mut ranges = Vec::with_capacity(reader.table.len() / CMBR_SECTION_ENTRY_SIZE);
+
+        for entry in reader.entries() {
+            if !CmbrSectionCodec::VARIANTS.contains(&entry.codec)
+                || entry.offset < sections_start as u64
+            {
+                return Err(LibCmbrError::new(LibCmbrErrorType::CorruptedFile));
+            }
+
+            let end = entry
+                .offset
+                .checked_add(entry.length)
+                .ok_or(LibCmbrError::new(LibCmbrErrorType::CorruptedFile))?;
+
+            if end > sections_end as u64 {
+                return Err(if is_streamed {
+                    LibCmbrError::new(LibCmbrErrorType::CorruptedFile)
+                } else {
+                    LibCmbrError::new(LibCmbrErrorType::TruncatedFile)
+                });
+            }
+
+            file_end = file_end.max(end);
+            ranges.push((entry.offset, end));
+
+            // Only streamed files can have a section more than once
+            if let Some(seen) = seen_kinds.get_mut(entry.kind as usize) {
+                if *seen && !is_streamed {
+                    return Err(LibCmbrError::new(LibCmbrErrorType::CorruptedFile));
+                }
+
+                *seen = true;
+            }
+        }
+
+        if file_end != sections_end as u64 {
+            return Err(LibCmbrError::new(LibCmbrErrorType::CorruptedFile));
+        }
+
+        // Sections can't share any bytes
+        ranges.sort_unstable();
+        if ranges.windows(2).any(|pair| pair[1].0 < pair[0].1) {
+            return Err(LibCmbrError::new(LibCmbrErrorType::CorruptedFile));
+        }
+
+        return Ok(reader);
+    }
+
+    pub fn from_mmap(mmap: &'a Mmap) -> Result<Self, LibCmbrError> {
+        return Self::new(&mmap[..]);
+    }
+
+    /// See `CmbrFileFlags`
+    pub fn flags(&self) -> u8 {
+        return self.flags;
+    }
+
+    pub fn is_streamed(&self) -> bool {
+        return self.flags & CmbrFileFlags::Streamed != 0;
+    }
+
+    /// Every entry of the section table, in order
+    pub fn entries(&self) -> impl Iterator<Item = CmbrSectionEntry> + 'a {
+        return self
+            .table
+            .chunks_exact(CMBR_SECTION_ENTRY_SIZE)
+            // SAFE: Safe. `chunks_exact` makes sure that the lengths are correct
+            .map(|entry| {
+                CmbrSectionEntry::from_bytes(unsafe { entry.try_into().unwrap_unchecked() })
+            });
+    }
+
+    /// Every section table entry of `kind` (See `CmbrSectionKind`). Only streamed files can have
+    /// more than one
+    pub fn sections(&self, kind: u8) -> impl Iterator<Item = CmbrSectionEntry> + 'a {
+        return self.entries().filter(move |entry| entry.kind == kind);
+    }
+
+    /// Returns the section table entry of `kind` (See `CmbrSectionKind`), if the file has it
+    pub fn section(&self, kind: u8) -> Option<CmbrSectionEntry> {
+        return self.sections(kind).next();
+    }
+
+    /// Whether any of the sections are compressed
+    pub fn is_compressed(&self) -> bool {
+        return self
+            .entries()
+            .any(|entry| entry.codec != CmbrSectionCodec::Raw);
+    }
+
+    /// Decodes every section of `kind`, in order. Files that aren't streamed must have exactly one
+    fn read_sections<T: serde::de::DeserializeOwned>(
+        &self,
+        kind: u8,
+    ) -> Result<Vec<T>, LibCmbrError> {
+        let sections = self
+            .sections(kind)
+            .map(|entry| {
+                // The bounds are checked in `new`
+                let bytes =
+                    &self.bytes[entry.offset as usize..(entry.offset + entry.length) as usize];
+                let bytes = decode_section(bytes, entry.codec)?;
+
+                return bitcode::deserialize(&bytes)
+                    .map_err(|_| LibCmbrError::new(LibCmbrErrorType::CorruptedFile));
+            })
+            .collect::<Result<Vec<T>, LibCmbrError>>()?;
+
+        if sections.is_empty() && !self.is_streamed() {
+            return Err(LibCmbrError::new(LibCmbrErrorType::CorruptedFile));
+        }
+
+        return Ok(sections);
+    }
+
+    /// Decodes every section of `kind` whose value is a map from game Ids, and merges them
+    fn read_game_sections<V: serde::de::DeserializeOwned>(
+        &self,
+        kind: u8,
+    ) -> Result<LiteMap<u32, V>, LibCmbrError> {
+        let mut merged = LiteMap::new();
+
+        for section in self.read_sections::<LiteMap<u32, V>>(kind)? {
+            for (id, value) in section {
+                merged.insert(id, value);
+            }
+        }
+
+        return Ok(merged);
+    }
+
+    /// Decodes only the headers section
+    pub fn read_headers(&self) -> Result<CmbrHeadersSection<'static>, LibCmbrError> {
+        return self.read_game_sections(CmbrSectionKind::Headers);
+    }
+
+    /// Decodes only the moves section
+    pub fn read_moves(&self) -> Result<CmbrMovesSection<'static>, LibCmbrError> {
+        return self.read_game_sections(CmbrSectionKind::Moves);
+    }
+
+    /// Decodes only the comments section
+    pub fn read_comments(&self) -> Result<CmbrCommentsSection<'static>, LibCmbrError> {
+        return self.read_game_sections(CmbrSectionKind::Comments);
+    }
+
+    /// Decodes only the positions section. Every position is stored once, so the keys of the
+    /// positions of a batch can change when it's merged with the previous ones (See
+    /// `move_positions`)
+    pub fn read_positions(&self) -> Result<CmbrPositionsSection<'static>, LibCmbrError> {
+        let kind = CmbrSectionKind::Positions;
+
+        // Files converted without position tables don't have a positions section
+        if self.section(kind).is_none() {
+            return Ok(CmbrPositionsSection::default());
+        }
+
+        let mut sections = self
+            .read_sections::<CmbrPositionsSection>(kind)?
+            .into_iter();
+        let Some(mut merged) = sections.next() else {
+            return Ok(CmbrPositionsSection::default());
+        };
+
+        for section in sections {
+            for (id, mut positions) in section.games {
+                move_positions(
+                    positions.to_mut(),
+                    &section.encountered_positions,
+                    merged.encountered_positions.to_mut(),
+                );
+                merged.games.insert(id, positions);
+            }
+        }
+
+        return Ok(merged);
+    }
+
+    /// Decodes the whole file
+    pub fn read(&self) -> Result<CmbrFile, LibCmbrError> {
+        return Ok(CmbrFile::from_sections(
+            self.is_compressed(),
+            self.read_headers()?,
+            self.read_moves()?,
+            self.read_comments()?,
+            self.read_positions()?,
+        ));
+    }
+}
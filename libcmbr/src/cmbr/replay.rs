@@ -0,0 +1,362 @@
+use super::structs::*;
+use super::{CmbrMove, CmbrPackedPosition, DecodedCmbrMv, SanToCmbrMvConvertor};
+use crate::error::{LibCmbrError, LibCmbrErrorType};
+use crate::pgn::VariationPointerT;
+
+use shakmaty::Move;
+
+use std::collections::HashMap;
+use std::mem;
+
+/// Replayed by games without a main variation
+static EMPTY_VARIATION: CmbrVariation = CmbrVariation {
+    starts_at: 0,
+    moves: Vec::new(),
+    comments: Vec::new(),
+};
+
+/// A move of a variation, and what's attached to it
+#[derive(Debug, Clone)]
+pub struct CmbrReplayStep<'a, P> {
+    /// The half move the move reached, counted the same way as in `MoveId`
+    pub ply: u16,
+    /// The position after the move
+    pub board: P,
+    /// `None` for a null move
+    pub shakmaty_move: Option<Move>,
+    /// The comments after the move. See `CmbrVariation::comments`
+    pub comments: Vec<&'a CmbrComment>,
+    /// The NAGs attached to the move
+    pub nags: Vec<u8>,
+    /// The variations that are alternatives to the move
+    pub variations: Vec<VariationPointerT>,
+}
+
+/// What comes before the first move of a variation. See `CmbrVariationReplay::leading`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CmbrReplayLeading<'a> {
+    /// The comments before the first move, like `{intro}` in `{intro} 1. e4`
+    pub comments: &'a [CmbrComment],
+    /// NAGs that aren't attached to any move
+    pub nags: Vec<u8>,
+    /// Variation pointers that aren't after any move
+    pub variations: Vec<VariationPointerT>,
+}
+
+/// Replays a variation move by move. Created by `CmbrVariation::iter_positions`. Stops after
+/// the first CMBR-MV that can't be replayed
+#[derive(Debug, Clone)]
+pub struct CmbrVariationReplay<'a, P> {
+    variation: &'a CmbrVariation,
+    board: P,
+    previous_board: P,
+    index: usize,
+    /// The first comment that hasn't been returned. Comments are ordered by their half move
+    comment_index: usize,
+    ply: u16,
+}
+
+impl<'a, P: CmbrPosition> CmbrVariationReplay<'a, P> {
+    /// The position after the last replayed move
+    pub fn board(&self) -> &P {
+        return &self.board;
+    }
+
+    /// The position before the last replayed move, which its alternatives start from
+    pub fn previous_board(&self) -> &P {
+        return &self.previous_board;
+    }
+
+    /// The half move of `board`
+    pub fn ply(&self) -> u16 {
+        return self.ply;
+    }
+
+    /// The comments, NAGs and variation pointers before the first move, which no step includes.
+    /// Fails if a CMBR-MV before the first move isn't valid
+    pub fn leading(&self) -> Result<CmbrReplayLeading<'a>, LibCmbrError> {
+        let variation = self.variation;
+        let mut leading = CmbrReplayLeading {
+            comments: &variation.comments[..leading_comment_count(variation)],
+            ..Default::default()
+        };
+
+        for cmbr in &variation.moves {
+            match CmbrMove::try_from(*cmbr)? {
+                CmbrMove::Nag(nag) => leading.nags.push(nag),
+                CmbrMove::VariationPointer(pointer) => {
+                    leading.variations.push(pointer as VariationPointerT)
+                }
+                _ => break,
+            }
+        }
+
+        return Ok(leading);
+    }
+
+    fn step(&mut self) -> Result<Option<CmbrReplayStep<'a, P>>, LibCmbrError> {
+        let moves = &self.variation.moves;
+
+        // What comes before the first move is returned by `leading`
+        let cmbr = loop {
+            let Some(cmbr) = moves.get(self.index) else {
+                return Ok(None);
+            };
+
+            self.index += 1;
+
+            if CmbrMove::try_from(*cmbr)?.is_move() {
+                break *cmbr;
+            }
+        };
+
+        let mut board = self.board.clone();
+        let shakmaty_move = match SanToCmbrMvConvertor::cmbr_to_san(&mut board, cmbr)? {
+            DecodedCmbrMv::Move { shakmaty_move, .. } => Some(shakmaty_move),
+            _ => None,
+        };
+
+        self.previous_board = mem::replace(&mut self.board, board);
+        self.ply += 1;
+
+        let mut nags = Vec::new();
+        let mut variations = Vec::new();
+
+        while let Some(cmbr) = moves.get(self.index) {
+            match CmbrMove::try_from(*cmbr)? {
+                CmbrMove::Nag(nag) => nags.push(nag),
+                CmbrMove::VariationPointer(pointer) => {
+                    variations.push(pointer as VariationPointerT)
+                }
+                _ => break,
+            }
+
+            self.index += 1;
+        }
+
+        let comments = &self.variation.comments[self.comment_index..];
+        let comment_count = comments
+            .iter()
+            .take_while(|c| c.half_move <= self.ply)
+            .count();
+        self.comment_index += comment_count;
+
+        return Ok(Some(CmbrReplayStep {
+            ply: self.ply,
+            board: self.board.clone(),
+            shakmaty_move,
+            comments: comments[..comment_count].iter().collect(),
+            nags,
+            variations,
+        }));
+    }
+}
+
+impl<'a, P: CmbrPosition> Iterator for CmbrVariationReplay<'a, P> {
+    type Item = Result<CmbrReplayStep<'a, P>, LibCmbrError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let step = self.step().transpose();
+
+        if let Some(Err(_)) = step {
+            self.index = self.variation.moves.len();
+        }
+
+        return step;
+    }
+}
+
+impl CmbrVariation {
+    /// Replays the variation from `board`, the position it starts from. See
+    /// `CmbrGame::variation_board`
+    pub fn iter_positions<P: CmbrPosition>(&self, board: P) -> CmbrVariationReplay<'_, P> {
+        return CmbrVariationReplay {
+            variation: self,
+            previous_board: board.clone(),
+            board,
+            index: 0,
+            comment_index: leading_comment_count(self),
+            ply: self.starts_at,
+        };
+    }
+}
+
+/// The amount of comments before the first move of `variation`
+fn leading_comment_count(variation: &CmbrVariation) -> usize {
+    return variation
+        .comments
+        .iter()
+        .take_while(|c| c.half_move <= variation.starts_at)
+        .count();
+}
+
+#[derive(Debug, Clone)]
+struct CmbrCursorFrame<'a, P> {
+    variation: VariationPointerT,
+    replay: CmbrVariationReplay<'a, P>,
+    alternatives: Vec<VariationPointerT>,
+}
+
+/// Walks the variations of a game. Created by `CmbrGame::cursor`. A variation can be entered
+/// right after the move it's an alternative to, and left to continue its parent from there
+#[derive(Debug, Clone)]
+pub struct CmbrGameCursor<'a, P> {
+    game: &'a CmbrGame,
+    frames: Vec<CmbrCursorFrame<'a, P>>,
+}
+
+impl<'a, P: CmbrPosition> CmbrGameCursor<'a, P> {
+    fn frame(&self) -> &CmbrCursorFrame<'a, P> {
+        // SAFE: Safe. There's always at least the main variation's frame
+        return unsafe { self.frames.last().unwrap_unchecked() };
+    }
+
+    /// Replays the next move of the current variation. Returns `None` at its end
+    pub fn next_step(&mut self) -> Option<Result<CmbrReplayStep<'a, P>, LibCmbrError>> {
+        // SAFE: Safe. There's always at least the main variation's frame
+        let frame = unsafe { self.frames.last_mut().unwrap_unchecked() };
+        let step = frame.replay.next();
+
+        frame.alternatives = match &step {
+            Some(Ok(step)) => step.variations.clone(),
+            _ => Vec::new(),
+        };
+
+        return step;
+    }
+
+    /// The current variation's pointer
+    pub fn variation(&self) -> VariationPointerT {
+        return self.frame().variation;
+    }
+
+    /// How many variations were entered. 0 in the main variation
+    pub fn depth(&self) -> usize {
+        return self.frames.len() - 1;
+    }
+
+    /// The position after the last replayed move
+    pub fn board(&self) -> &P {
+        return self.frame().replay.board();
+    }
+
+    /// The half move of `board`
+    pub fn ply(&self) -> u16 {
+        return self.frame().replay.ply();
+    }
+
+    /// What comes before the first move of the current variation. See
+    /// `CmbrVariationReplay::leading`
+    pub fn leading(&self) -> Result<CmbrReplayLeading<'a>, LibCmbrError> {
+        return self.frame().replay.leading();
+    }
+
+    /// The variations that are alternatives to the last replayed move
+    pub fn alternatives(&self) -> &[VariationPointerT] {
+        return &self.frame().alternatives;
+    }
+
+    /// Enters one of the `alternatives`. Fails with `NotAnAlternative` if `pointer` isn't one
+    pub fn enter(&mut self, pointer: VariationPointerT) -> Result<(), LibCmbrError> {
+        let variation = self
+            .game
+            .variations
+            .get(&pointer)
+            .filter(|_| self.alternatives().contains(&pointer))
+            .ok_or(LibCmbrError::new(LibCmbrErrorType::NotAnAlternative))?;
+
+        let board = self.frame().replay.previous_board().clone();
+
+        self.frames.push(CmbrCursorFrame {
+            variation: pointer,
+            replay: variation.iter_positions(board),
+            alternatives: Vec::new(),
+        });
+
+        return Ok(());
+    }
+
+    /// Leaves the current variation, and continues its parent after the move the variation is
+    /// an alternative to. Returns false in the main variation
+    pub fn exit(&mut self) -> bool {
+        if self.frames.len() == 1 {
+            return false;
+        }
+
+        self.frames.pop();
+        return true;
+    }
+}
+
+impl CmbrGame {
+    /// Replays the main variation. `P` should match `variant`
+    pub fn replay<P: CmbrPosition>(&self) -> Result<CmbrVariationReplay<'_, P>, LibCmbrError> {
+        let main_variation = self.variations.get(&0).unwrap_or(&EMPTY_VARIATION);
+
+        return Ok(main_variation.iter_positions(self.starting_board()?));
+    }
+
+    /// Creates a cursor at the start of the main variation. `P` should match `variant`
+    pub fn cursor<P: CmbrPosition>(&self) -> Result<CmbrGameCursor<'_, P>, LibCmbrError> {
+        return Ok(CmbrGameCursor {
+            game: self,
+            frames: vec![CmbrCursorFrame {
+                variation: 0,
+                replay: self.replay()?,
+                alternatives: Vec::new(),
+            }],
+        });
+    }
+
+    /// The position a variation starts from. It's looked up in `positions` (See
+    /// `CmbrFile::encountered_positions`) if the game has it, and otherwise found from the
+    /// starting position by replaying the variations that lead to it. `P` should match
+    /// `variant`
+    pub fn variation_board<P: CmbrPosition>(
+        &self,
+        pointer: VariationPointerT,
+        positions: &HashMap<PositionKey, CmbrPackedPosition>,
+    ) -> Result<P, LibCmbrError> {
+        let missing = LibCmbrError::new(LibCmbrErrorType::MissingPosition);
+        let variation = self.variations.get(&pointer).ok_or(missing)?;
+
+        let move_id = (pointer << 16) | variation.starts_at as MoveId;
+        let stored = self
+            .encountered_positions
+            .get(&move_id)
+            .and_then(|key| positions.get(key));
+
+        if let Some(position) = stored {
+            return position.to_position(self.castling_mode());
+        }
+
+        if pointer == 0 {
+            return self.starting_board();
+        }
+
+        // A variation is always referenced by a variation with a smaller pointer
+        let pointer_cmbr: CmbrMv = CmbrMove::VariationPointer(pointer as u16).into();
+        let parent = self
+            .variations
+            .iter()
+            .take_while(|(id, _)| **id < pointer)
+            .find(|(_, parent)| parent.moves.contains(&pointer_cmbr))
+            .ok_or(missing)?;
+
+        let board = self.variation_board(*parent.0, positions)?;
+        let mut replay = parent.1.iter_positions::<P>(board);
+
+        // Variations before the first move of their parent start from the same position
+        if replay.leading()?.variations.contains(&pointer) {
+            return Ok(replay.board().clone());
+        }
+
+        while let Some(step) = replay.next() {
+            if step?.variations.contains(&pointer) {
+                return Ok(replay.previous_board().clone());
+            }
+        }
+
+        return Err(missing);
+    }
+}
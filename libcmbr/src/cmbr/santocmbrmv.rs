@@ -1,95 +1,158 @@
 use super::structs::*;
+use super::CmbrMove;
 use crate::error::{LibCmbrError, LibCmbrErrorType};
+use crate::pgn::VariationPointerT;
 
-use shakmaty::san::{San::*, SanPlus, Suffix};
-use shakmaty::{Chess, Color, Position, Role, Square};
+use shakmaty::san::{San, SanPlus, Suffix};
+use shakmaty::{CastlingSide, Color, FromSetup, Move, Piece, Position, Role, Square};
 
 use std::collections::HashMap;
 use std::error::Error;
 use std::mem::size_of;
 
+/// A CMBR-MV decoded against the position it was played in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodedCmbrMv {
+    /// A move that is played on the board
+    Move {
+        shakmaty_move: Move,
+        san: SanPlus,
+        /// The lower 8 bits of the CMBR-MV. See `CmbrMvFlags`
+        flags: u8,
+    },
+    /// A null move, which only passes the turn. Written as `--` in PGN
+    NullMove,
+    /// A NAG attached to the previous move
+    Nag(u8),
+    /// A pointer to a variation that is an alternative to the previous move
+    VariationPointer(VariationPointerT),
+}
+
+/// A SAN of at most 8 bytes, packed into an integer so that looking it up doesn't allocate.
+/// Longer SANs aren't cached
+type PackedSan = u64;
+
+fn pack_san(san_bytes: &[u8]) -> Option<PackedSan> {
+    if san_bytes.len() > size_of::<PackedSan>() {
+        return None;
+    }
+
+    let mut packed = [0u8; size_of::<PackedSan>()];
+    packed[..san_bytes.len()].copy_from_slice(san_bytes);
+
+    return Some(PackedSan::from_le_bytes(packed));
+}
+
+#[derive(Debug, Clone)]
+struct CachedMove {
+    shakmaty_move: Move,
+    cmbr: CmbrMv,
+}
+
+type SanTable = HashMap<(PositionKey, PackedSan), CachedMove>;
+
+/// The memory used by one bucket of a `SanTable`, including its control byte
+pub(crate) const SAN_TABLE_BUCKET_SIZE: usize =
+    size_of::<((PositionKey, PackedSan), CachedMove)>() + 1;
+
+/// Used by `SanToCmbrMvConvertor` when it's given a memory limit of 0
+pub const DEFAULT_SAN_CACHE_MEMORY_LIMIT: u64 = 16 * 1024 * 1024;
+
 #[derive(Debug)]
 pub struct SanToCmbrMvConvertor {
-    table: HashMap<String, CmbrMv>,
+    /// (Hash of the position, keyed like `CmbrFile::encountered_positions`, SAN) -> CMBR-MV. The
+    /// same SAN can denote different moves in different positions. Once it holds
+    /// `table_capacity` moves, it replaces `previous_table`, so the moves that haven't been used
+    /// since are evicted
+    table: SanTable,
+    /// Moves found here are moved back to `table`
+    previous_table: SanTable,
+    table_capacity: usize,
 }
 
 impl SanToCmbrMvConvertor {
+    /// `memory_limit_in_bytes` bounds the memory used by the cache, including the memory that is
+    /// briefly used while it grows. A limit of 0 means `DEFAULT_SAN_CACHE_MEMORY_LIMIT`
     pub fn new(memory_limit_in_bytes: u64) -> Self {
+        let memory_limit_in_bytes = if memory_limit_in_bytes == 0 {
+            DEFAULT_SAN_CACHE_MEMORY_LIMIT
+        } else {
+            memory_limit_in_bytes
+        };
+
+        // Both tables can hold `buckets` buckets, and while `table` grows its old buckets are
+        // still allocated, so at most 2.5 times that
+        let max_buckets =
+            (memory_limit_in_bytes as usize).saturating_mul(2) / (5 * SAN_TABLE_BUCKET_SIZE);
+        let buckets = if max_buckets == 0 {
+            0
+        } else {
+            1 << max_buckets.ilog2()
+        };
+
         return Self {
-            table: HashMap::with_capacity(memory_limit_in_bytes as usize / size_of::<CmbrMv>()),
+            table: HashMap::new(),
+            previous_table: HashMap::new(),
+            // A table with this many buckets holds 7/8 of that many moves before it grows
+            table_capacity: buckets / 8 * 7,
         };
     }
 
-    pub fn shakmaty_move_to_cmbr(
-        role: &Role,
-        from: &Square,
-        to: &Square,
-        capture: &bool,
-        promotion: &Option<Role>,
-        suffix: &Option<Suffix>,
-        color: u8,
-    ) -> CmbrMv {
-        let mut cmbr = 0u32;
+    /// The amount of moves that are cached
+    pub fn cached_move_count(&self) -> usize {
+        return self.table.len() + self.previous_table.len();
+    }
 
-        let role_to_cmbr: &[u32] = &[
-            0b0000, 0b0001, 0b0010, 0b0011, 0b0100, 0b0101, 0b1000, 0b1001, 0b1010, 0b1011, 0b1100,
-            0b1101,
-        ];
+    fn cached_move(&mut self, key: &(PositionKey, PackedSan)) -> Option<CachedMove> {
+        if let Some(cached) = self.table.get(key) {
+            return Some(cached.clone());
+        }
 
-        let promotion_to_cmbr: &[u32] = &[0, 0b01010000, 0b01000000, 0b01100000, 0b01110000];
+        let cached = self.previous_table.remove(key)?;
+        self.cache_move(*key, cached.clone());
 
-        if promotion.is_some() {
-            // SAFE: Safe
-            cmbr |= promotion_to_cmbr[unsafe { promotion.unwrap_unchecked() } as usize - 1];
-        }
+        return Some(cached);
+    }
 
-        if suffix.is_some() {
-            // SAFE: Safe
-            cmbr |= Self::shakmaty_suffix_to_flag(unsafe { suffix.unwrap_unchecked() }) as u32;
+    fn cache_move(&mut self, key: (PositionKey, PackedSan), cached: CachedMove) {
+        if self.table_capacity == 0 {
+            return;
         }
 
-        if *capture {
-            cmbr |= 0b00000100;
+        if self.table.len() >= self.table_capacity {
+            // Free the old moves before `table` starts growing again
+            self.previous_table = std::mem::take(&mut self.table);
         }
 
-        cmbr |= role_to_cmbr[*role as usize - 1 + 6 * color as usize] << 8;
-        cmbr |= (*from as u32) << (8 + 4);
-        cmbr |= (*to as u32) << (8 + 4 + 6);
-
-        cmbr.into()
-    }
-
-    fn shakmaty_suffix_to_flag(suffix: Suffix) -> u8 {
-        return match suffix {
-            Suffix::Check => CmbrMvFlags::FlagCheck,
-            Suffix::Checkmate => CmbrMvFlags::FlagMate,
-        };
+        self.table.insert(key, cached);
     }
 
-    /// Inputs a SAN string and generates a CMBR-MV from it
-    pub fn san_to_cmbr(
-        &mut self,
-        board: &mut Chess,
-        san_bytes: &[u8],
-    ) -> Result<CmbrMv, Box<dyn Error>> {
-        // SAFE: Safe if the function is called correctly.
-        let san_string = unsafe { std::str::from_utf8_unchecked(san_bytes) }.to_owned();
-
-        let san: SanPlus = san_string.parse()?;
-        let san_move = san.san.to_move(board)?;
-
-        let cmbr = self.table.get(&san_string);
-        if cmbr.is_some() {
-            // SAFE: Safe
-            let cmbr = unsafe { *cmbr.unwrap_unchecked() };
-            board.play_unchecked(&san_move);
-
-            return Ok(cmbr);
+    pub fn shakmaty_move_to_cmbr(
+        role: &Role,
+        from: &Square,
+        to: &Square,
+        capture: &bool,
+        promotion: &Option<Role>,
+        suffix: &Option<Suffix>,
+        color: u8,
+    ) -> CmbrMv {
+        return CmbrMove::Normal {
+            piece: Piece {
+                color: Color::from_white(color == 0),
+                role: *role,
+            },
+            from: *from,
+            to: *to,
+            capture: *capture,
+            promotion: *promotion,
+            suffix: *suffix,
         }
+        .into();
+    }
 
-        let color = board.turn();
-
-        let cmbr_move = match &san_move {
+    /// Encodes a move played by `color`. `suffix` is stored as is
+    pub fn move_to_cmbr(shakmaty_move: &Move, color: Color, suffix: Option<Suffix>) -> CmbrMv {
+        return match shakmaty_move {
             shakmaty::Move::Normal {
                 role,
                 from,
@@ -102,35 +165,24 @@ impl SanToCmbrMvConvertor {
                 to,
                 &capture.is_some(),
                 promotion,
-                &san.suffix,
+                &suffix,
                 (color == Color::Black) as u8,
             ),
 
-            #[rustfmt::skip]
-            shakmaty::Move::Castle { king: _, rook: _ } => {
-                let mut cmbr = 0u32;
-
-                let side = if let Castle(side) = san.san {side} else {unreachable!()};
-                cmbr |= if color == Color::Black {1u32 << 3} else {0};
-
-                cmbr |= match side {
-                    shakmaty::CastlingSide::KingSide  => 0b100u32,
-                    shakmaty::CastlingSide::QueenSide => 0b101u32,
-                };
-
-                if san.suffix.is_some() {
-                    // SAFE: Safe
-                    cmbr |= (Self::shakmaty_suffix_to_flag(unsafe { san.suffix.unwrap_unchecked() }) as u32) as u32;
-                }
-
-                cmbr.into()
+            // The castling side is enough to find the king and the rook, even in Chess960
+            shakmaty::Move::Castle { king, rook } => CmbrMove::Castle {
+                color,
+                side: CastlingSide::from_king_side(rook > king),
+                suffix,
             }
+            .into(),
 
-            shakmaty::Move::Put { role: _, to: _ } => {
-                return Err(Box::new(LibCmbrError::new(
-                    LibCmbrErrorType::CrazyHouseNotSupported,
-                )));
+            shakmaty::Move::Put { role, to } => CmbrMove::Drop {
+                piece: Piece { color, role: *role },
+                to: *to,
+                suffix,
             }
+            .into(),
 
             shakmaty::Move::EnPassant { from, to } => Self::shakmaty_move_to_cmbr(
                 &Role::Pawn,
@@ -138,15 +190,143 @@ impl SanToCmbrMvConvertor {
                 to,
                 &true,
                 &None,
-                &san.suffix,
+                &suffix,
                 (color == Color::Black) as u8,
             ),
         };
+    }
+
+    /// Inputs a SAN string and generates a CMBR-MV from it
+    pub fn san_to_cmbr<P: CmbrPosition>(
+        &mut self,
+        board: &mut P,
+        san_bytes: &[u8],
+    ) -> Result<CmbrMv, Box<dyn Error>> {
+        // SAFE: Safe if the function is called correctly.
+        let san: SanPlus = unsafe { std::str::from_utf8_unchecked(san_bytes) }.parse()?;
+
+        // `--` and `Z0`
+        if san.san == San::Null {
+            let color = board.turn();
+            Self::play_null_move(board)?;
+
+            return Ok(CmbrMove::NullMove { color }.into());
+        }
+
+        let key = pack_san(san_bytes).map(|packed| (position_hash(board), packed));
+
+        if let Some(cached) = key.and_then(|key| self.cached_move(&key)) {
+            // The hashes of different positions can collide
+            if san.san.matches(&cached.shakmaty_move) && board.is_legal(&cached.shakmaty_move) {
+                board.play_unchecked(&cached.shakmaty_move);
+
+                return Ok(cached.cmbr);
+            }
+        }
+
+        let san_move = san.san.to_move(board)?;
+        let color = board.turn();
+
+        let cmbr_move = Self::move_to_cmbr(&san_move, color, san.suffix);
 
         // SAFE: Safe
         board.play_unchecked(&san_move);
-        self.table.insert(san_string, cmbr_move);
+
+        if let Some(key) = key {
+            self.cache_move(
+                key,
+                CachedMove {
+                    shakmaty_move: san_move,
+                    cmbr: cmbr_move,
+                },
+            );
+        }
 
         return Ok(cmbr_move);
     }
+
+    /// Finds the legal move on `board` that a CMBR-MV denotes. Doesn't play the move.
+    /// NAGs and variation pointers are returned as is.
+    pub fn cmbr_to_shakmaty_move<P: Position + Clone>(
+        board: &P,
+        cmbr: CmbrMv,
+    ) -> Result<DecodedCmbrMv, LibCmbrError> {
+        let cmbr_move = CmbrMove::try_from(cmbr)?;
+        let legal_moves = board.legal_moves();
+
+        let shakmaty_move = match cmbr_move {
+            CmbrMove::VariationPointer(pointer) => {
+                return Ok(DecodedCmbrMv::VariationPointer(
+                    pointer as VariationPointerT,
+                ))
+            }
+
+            CmbrMove::Nag(nag) => return Ok(DecodedCmbrMv::Nag(nag)),
+
+            CmbrMove::NullMove { color } => {
+                if color != board.turn() || board.is_check() {
+                    return Err(LibCmbrError::new(LibCmbrErrorType::IllegalCmbrMv));
+                }
+
+                return Ok(DecodedCmbrMv::NullMove);
+            }
+
+            CmbrMove::Castle { side, .. } => legal_moves
+                .into_iter()
+                .find(|m| m.castling_side() == Some(side)),
+
+            CmbrMove::Drop { piece, to, .. } => legal_moves.into_iter().find(
+                |m| matches!(m, Move::Put { role, .. } if *role == piece.role && m.to() == to),
+            ),
+
+            CmbrMove::Normal {
+                from,
+                to,
+                promotion,
+                ..
+            } => legal_moves
+                .into_iter()
+                .find(|m| m.from() == Some(from) && m.to() == to && m.promotion() == promotion),
+        };
+
+        let shakmaty_move =
+            shakmaty_move.ok_or(LibCmbrError::new(LibCmbrErrorType::IllegalCmbrMv))?;
+        let san = SanPlus::from_move(board.clone(), &shakmaty_move);
+
+        return Ok(DecodedCmbrMv::Move {
+            shakmaty_move,
+            san,
+            flags: cmbr.to_u32() as u8,
+        });
+    }
+
+    /// Inputs a CMBR-MV and generates the SAN from it. The inverse of `san_to_cmbr`.
+    /// If the CMBR-MV is a move or a null move, it's played on `board`
+    pub fn cmbr_to_san<P: Position + FromSetup + Clone>(
+        board: &mut P,
+        cmbr: CmbrMv,
+    ) -> Result<DecodedCmbrMv, LibCmbrError> {
+        let decoded = Self::cmbr_to_shakmaty_move(board, cmbr)?;
+
+        match &decoded {
+            DecodedCmbrMv::Move { shakmaty_move, .. } => board.play_unchecked(shakmaty_move),
+            DecodedCmbrMv::NullMove => Self::play_null_move(board)?,
+            _ => {}
+        }
+
+        return Ok(decoded);
+    }
+
+    /// Passes the turn and discards the en passant square. Fails with `IllegalCmbrMv` if the
+    /// side to move is in check
+    pub fn play_null_move<P: Position + FromSetup + Clone>(
+        board: &mut P,
+    ) -> Result<(), LibCmbrError> {
+        *board = board
+            .clone()
+            .swap_turn()
+            .map_err(|_| LibCmbrError::new(LibCmbrErrorType::IllegalCmbrMv))?;
+
+        return Ok(());
+    }
 }
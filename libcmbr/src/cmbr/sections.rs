@@ -0,0 +1,271 @@
+use super::{
+    CmbrComment, CmbrFen, CmbrFile, CmbrGame, CmbrMv, CmbrPackedPosition, CmbrVariation, MoveId,
+    PositionKey,
+};
+use crate::error::{LibCmbrError, LibCmbrErrorType};
+use crate::pgn::VariationPointerT;
+use crate::utils::def_enum;
+
+use litemap::LiteMap;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+def_enum! (
+    #[doc = "An enum denoting the kinds of sections a CMBR file can have"]
+    pub CmbrSectionKind => u8 {
+        Headers   => 0,
+        Moves     => 1,
+        Comments  => 2,
+        Positions => 3,
+});
+
+def_enum! (
+    #[doc = "An enum denoting how the bytes of a section are encoded"]
+    pub CmbrSectionCodec => u8 {
+        Raw  => 0,
+        Zstd => 1,
+});
+
+/// An entry in the section table. `offset` is from the start of the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CmbrSectionEntry {
+    pub kind: u8,
+    pub codec: u8,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Kind (u8), codec (u8), offset (u64) and length (u64)
+pub const CMBR_SECTION_ENTRY_SIZE: usize = 2 + 2 * std::mem::size_of::<u64>();
+
+impl CmbrSectionCodec {
+    pub fn for_compression(is_compressed: bool) -> u8 {
+        return if is_compressed { Self::Zstd } else { Self::Raw };
+    }
+}
+
+impl CmbrSectionEntry {
+    /// The entry as it's laid out in the section table
+    pub fn to_bytes(&self) -> [u8; CMBR_SECTION_ENTRY_SIZE] {
+        let mut bytes = [0; CMBR_SECTION_ENTRY_SIZE];
+        bytes[0] = self.kind;
+        bytes[1] = self.codec;
+        bytes[2..10].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[10..18].copy_from_slice(&self.length.to_le_bytes());
+
+        return bytes;
+    }
+
+    pub fn from_bytes(bytes: &[u8; CMBR_SECTION_ENTRY_SIZE]) -> Self {
+        return Self {
+            kind: bytes[0],
+            codec: bytes[1],
+            // SAFE: Safe. The lengths of the slices are constant
+            offset: u64::from_le_bytes(unsafe { bytes[2..10].try_into().unwrap_unchecked() }),
+            length: u64::from_le_bytes(unsafe { bytes[10..18].try_into().unwrap_unchecked() }),
+        };
+    }
+}
+
+/// Headers and the result of a game. Borrowed from the game when it's serialized, and owned
+/// when it's read
+#[cfg_attr(feature = "bitcode", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct CmbrGameHeaders<'a> {
+    pub headers: Cow<'a, [(String, String)]>,
+    pub result: char,
+    pub variant: u8,
+    pub starting_position: Cow<'a, Option<CmbrFen>>,
+}
+
+/// The moves of a variation, without its comments
+#[cfg_attr(feature = "bitcode", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct CmbrVariationMoves<'a> {
+    pub starts_at: u16,
+    pub moves: Cow<'a, [CmbrMv]>,
+}
+
+/// Game Id -> headers
+pub type CmbrHeadersSection<'a> = LiteMap<u32, CmbrGameHeaders<'a>>;
+/// Game Id -> variation pointer -> moves
+pub type CmbrMovesSection<'a> = LiteMap<u32, LiteMap<VariationPointerT, CmbrVariationMoves<'a>>>;
+/// `CmbrVariation::comments`
+pub type CmbrVariationComments<'a> = Cow<'a, [CmbrComment]>;
+/// The comments of a game
+#[cfg_attr(feature = "bitcode", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct CmbrGameComments<'a> {
+    /// `CmbrGame::escape_comments`
+    pub escape_comments: Cow<'a, [String]>,
+    /// Variation pointer -> `CmbrVariation::comments`. Variations without comments are left out
+    pub variations: LiteMap<VariationPointerT, CmbrVariationComments<'a>>,
+}
+
+/// Game Id -> comments. Games without comments are left out
+pub type CmbrCommentsSection<'a> = LiteMap<u32, CmbrGameComments<'a>>;
+
+/// The positions of every game, and the position table they point into. Borrowed from the file
+/// when it's serialized, and owned when it's read
+#[cfg_attr(feature = "bitcode", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct CmbrPositionsSection<'a> {
+    /// Game Id -> `CmbrGame::encountered_positions`
+    pub games: LiteMap<u32, Cow<'a, HashMap<MoveId, PositionKey>>>,
+    pub encountered_positions: Cow<'a, HashMap<PositionKey, CmbrPackedPosition>>,
+}
+
+pub(crate) fn encode_section(bytes: Vec<u8>, codec: u8, compression_level: i32) -> Vec<u8> {
+    return match codec {
+        CmbrSectionCodec::Zstd => zstd::encode_all(&bytes[..], compression_level).unwrap(),
+        _ => bytes,
+    };
+}
+
+pub(crate) fn decode_section(bytes: &[u8], codec: u8) -> Result<Cow<'_, [u8]>, LibCmbrError> {
+    return match codec {
+        CmbrSectionCodec::Raw => Ok(Cow::Borrowed(bytes)),
+        CmbrSectionCodec::Zstd => zstd::decode_all(bytes)
+            .map(Cow::Owned)
+            .map_err(|_| LibCmbrError::new(LibCmbrErrorType::CorruptedFile)),
+        _ => Err(LibCmbrError::new(LibCmbrErrorType::CorruptedFile)),
+    };
+}
+
+impl CmbrFile {
+    fn sorted_games(&self) -> Vec<(u32, &CmbrGame)> {
+        let mut games: Vec<(u32, &CmbrGame)> = self.games.iter().map(|(k, v)| (*k, v)).collect();
+        games.sort_unstable_by_key(|(id, _)| *id);
+
+        return games;
+    }
+
+    pub fn headers_section(&self) -> CmbrHeadersSection<'_> {
+        return self
+            .sorted_games()
+            .into_iter()
+            .map(|(id, game)| {
+                let headers = CmbrGameHeaders {
+                    headers: Cow::Borrowed(&game.headers),
+                    result: game.result,
+                    variant: game.variant,
+                    starting_position: Cow::Borrowed(&game.starting_position),
+                };
+
+                (id, headers)
+            })
+            .collect();
+    }
+
+    pub fn moves_section(&self) -> CmbrMovesSection<'_> {
+        return self
+            .sorted_games()
+            .into_iter()
+            .map(|(id, game)| {
+                let variations = game
+                    .variations
+                    .iter()
+                    .map(|(pointer, variation)| {
+                        let moves = CmbrVariationMoves {
+                            starts_at: variation.starts_at,
+                            moves: Cow::Borrowed(&variation.moves),
+                        };
+
+                        (*pointer, moves)
+                    })
+                    .collect();
+
+                (id, variations)
+            })
+            .collect();
+    }
+
+    pub fn comments_section(&self) -> CmbrCommentsSection<'_> {
+        return self
+            .sorted_games()
+            .into_iter()
+            .filter_map(|(id, game)| {
+                let variations: LiteMap<VariationPointerT, CmbrVariationComments> = game
+                    .variations
+                    .iter()
+                    .filter(|(_, variation)| !variation.comments.is_empty())
+                    .map(|(pointer, variation)| (*pointer, Cow::Borrowed(&variation.comments[..])))
+                    .collect();
+
+                if variations.is_empty() && game.escape_comments.is_empty() {
+                    return None;
+                }
+
+                let comments = CmbrGameComments {
+                    escape_comments: Cow::Borrowed(&game.escape_comments),
+                    variations,
+                };
+
+                return Some((id, comments));
+            })
+            .collect();
+    }
+
+    pub fn positions_section(&self) -> CmbrPositionsSection<'_> {
+        return CmbrPositionsSection {
+            games: self
+                .sorted_games()
+                .into_iter()
+                .map(|(id, game)| (id, Cow::Borrowed(&game.encountered_positions)))
+                .collect(),
+            encountered_positions: Cow::Borrowed(&self.encountered_positions),
+        };
+    }
+
+    /// The inverse of splitting a file with the `*_section` functions
+    pub fn from_sections(
+        is_compressed: bool,
+        headers: CmbrHeadersSection<'_>,
+        moves: CmbrMovesSection<'_>,
+        mut comments: CmbrCommentsSection<'_>,
+        mut positions: CmbrPositionsSection<'_>,
+    ) -> Self {
+        let mut file = CmbrFile::new(is_compressed);
+        file.encountered_positions = positions.encountered_positions.into_owned();
+
+        for (id, game_headers) in headers {
+            let mut game = CmbrGame::new();
+            game.headers = game_headers.headers.into_owned();
+            game.result = game_headers.result;
+            game.variant = game_headers.variant;
+            game.starting_position = game_headers.starting_position.into_owned();
+            game.encountered_positions = positions
+                .games
+                .remove(&id)
+                .map(Cow::into_owned)
+                .unwrap_or_default();
+
+            file.games.insert(id, game);
+        }
+
+        for (id, variations) in moves {
+            // Every game has an entry in the headers section
+            let Some(game) = file.games.get_mut(&id) else {
+                continue;
+            };
+
+            let mut game_comments = comments.remove(&id).unwrap_or_default();
+            game.escape_comments = game_comments.escape_comments.into_owned();
+
+            for (pointer, variation_moves) in variations {
+                let mut variation = CmbrVariation::new(variation_moves.starts_at);
+                variation.moves = variation_moves.moves.into_owned();
+                variation.comments = game_comments
+                    .variations
+                    .remove(&pointer)
+                    .map(Cow::into_owned)
+                    .unwrap_or_default();
+
+                game.variations.insert(pointer, variation);
+            }
+        }
+
+        return file;
+    }
+}
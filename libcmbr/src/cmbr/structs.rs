@@ -1,8 +1,12 @@
 use std::collections::HashMap;
 
-use super::u24;
+use super::{u24, CmbrPackedPosition, DecodedCmbrMv, SanToCmbrMvConvertor};
+use crate::error::{LibCmbrError, LibCmbrErrorType};
 use crate::{pgn::VariationPointerT, utils::def_enum};
 use litemap::LiteMap;
+use shakmaty::fen::Fen;
+use shakmaty::zobrist::{Zobrist64, ZobristHash};
+use shakmaty::{CastlingMode, EnPassantMode, FromSetup, Position};
 
 def_enum! (
     #[doc = "An enum donating the flags that a CMBR-MV Can have"]
@@ -17,6 +21,7 @@ def_enum! (
         FlagPromotesKnight => (1 << 6) | 0b010000,
         FlagPromotesRook   => (1 << 6) | 0b100000,
         FlagPromotesQueen  => (1 << 6) | 0b110000,
+        FlagPromotesKing   => 0b110000, // Only in antichess
 
         FlagIsVariationPointer => 1 << 7 // If this flag is set, the first 16 bits of the CMBR are replaced with an index to the table of variations
 });
@@ -42,23 +47,128 @@ def_enum! (
         BlackLongCaslte => 0b1111,
 });
 
+def_enum! (
+    #[doc = "An enum denoting the variant a game is played in"]
+    pub CmbrVariant => u8 {
+        Standard => 0,
+        Chess960 => 1,
+        Crazyhouse => 2,
+        Atomic => 3,
+        Antichess => 4,
+        KingOfTheHill => 5,
+        ThreeCheck => 6,
+        Horde => 7,
+        RacingKings => 8,
+});
+
+def_enum! (
+    #[doc = "An enum denoting how a comment is written in PGN"]
+    pub CmbrCommentKind => u8 {
+        Brace => 0,  // `{...}`
+        Line => 1,   // `;...` up to the end of the line
+        Escape => 2, // `%...` on its own line
+});
+
+/// Evaluates `$body` with `$position` being the position type of `$variant` (See `CmbrVariant`)
+macro_rules! with_position_type {
+    ($variant:expr, $position:ident => $body:expr) => {
+        match $variant {
+            $crate::cmbr::CmbrVariant::Crazyhouse => {
+                type $position = shakmaty::variant::Crazyhouse;
+                $body
+            }
+            $crate::cmbr::CmbrVariant::Atomic => {
+                type $position = shakmaty::variant::Atomic;
+                $body
+            }
+            $crate::cmbr::CmbrVariant::Antichess => {
+                type $position = shakmaty::variant::Antichess;
+                $body
+            }
+            $crate::cmbr::CmbrVariant::KingOfTheHill => {
+                type $position = shakmaty::variant::KingOfTheHill;
+                $body
+            }
+            $crate::cmbr::CmbrVariant::ThreeCheck => {
+                type $position = shakmaty::variant::ThreeCheck;
+                $body
+            }
+            $crate::cmbr::CmbrVariant::Horde => {
+                type $position = shakmaty::variant::Horde;
+                $body
+            }
+            $crate::cmbr::CmbrVariant::RacingKings => {
+                type $position = shakmaty::variant::RacingKings;
+                $body
+            }
+            _ => {
+                type $position = shakmaty::Chess;
+                $body
+            }
+        }
+    };
+}
+
+pub(crate) use with_position_type;
+
+/// What `CmbrFile::from_ast` does with a game that can't be fully converted, like a game with
+/// an illegal move or an invalid FEN header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CmbrInvalidGamePolicy {
+    /// Stop the conversion, and return the error
+    Abort,
+    /// Leave the game out of the file. Positions only reached by it are left out as well
+    Skip,
+    /// Keep the game up to the point where the error was encountered
+    #[default]
+    KeepTruncated,
+}
+
+/// How `CmbrFile::from_ast_with_diagnostics` and the functions like it convert games
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CmbrConversionOptions {
+    /// Whether the sections are compressed. Ignored by `CmbrWriter`, which is told that when
+    /// it's created
+    pub is_compressed: bool,
+    /// Whether the position tables are built (See `CmbrFile::build_position_tables`)
+    pub with_positions: bool,
+    /// What's done with games that can't be fully converted
+    pub policy: CmbrInvalidGamePolicy,
+}
+
+impl Default for CmbrConversionOptions {
+    fn default() -> Self {
+        return Self {
+            is_compressed: false,
+            with_positions: true,
+            policy: CmbrInvalidGamePolicy::default(),
+        };
+    }
+}
+
+/// A position type that games can be converted in, like `Chess` or `Crazyhouse`. See `with_position_type`
+pub trait CmbrPosition: Position + FromSetup + ZobristHash + Clone + Default {}
+
+impl<P: Position + FromSetup + ZobristHash + Clone + Default> CmbrPosition for P {}
+
 /// CMBR Move representation
 pub type CmbrMv = u24;
 /// Calculated by `(VariationId << 16) | HalfMoveNumber`
 pub type MoveId = u32;
 pub type CmbrFen = String;
+/// The key of a position in `CmbrFile::encountered_positions`. It's the `Zobrist64` hash of the
+/// position, unless another position already has that key (See `insert_position`)
+pub type PositionKey = u64;
 
 /// A Struct denoting the structure of a CMBR file.
 #[derive(Debug, PartialEq, Eq, Clone)]
 #[cfg_attr(feature = "bitcode", derive(serde::Serialize, serde::Deserialize))]
 pub struct CmbrFile {
-    /// Header: `CMBR!`
-    magic_bytes: &'static str,
     pub is_compressed: bool,
     /// Game Id
     pub games: HashMap<u32, CmbrGame>,
-    /// Positions stored as FEN
-    pub encountered_positions: HashMap<u32, CmbrFen>,
+    /// Every position reached by any of the games
+    pub encountered_positions: HashMap<PositionKey, CmbrPackedPosition>,
 }
 
 /// A Struct denoting the structure of a game represented in CMBR
@@ -72,9 +182,17 @@ pub struct CmbrGame {
     ///     'd': Draw;
     ///     'u': Undefined.
     pub result: char,
+    /// See `CmbrVariant`
+    pub variant: u8,
+    /// The FEN of the starting position, if the game doesn't start from the standard one
+    pub starting_position: Option<CmbrFen>,
     /// Variation pointer (main variation is 0)
     pub variations: LiteMap<VariationPointerT, CmbrVariation>,
-    pub encountered_positions: HashMap<u32, u32>,
+    /// The positions reached after every move. Keys of `CmbrFile::encountered_positions`
+    pub encountered_positions: HashMap<MoveId, PositionKey>,
+    /// Escape comments (`%` lines) found before the tags. They're written before the tags too.
+    /// Ones between the tags and the movetext are comments of the main variation
+    pub escape_comments: Vec<String>,
 }
 
 /// A Struct denoting the structure of a variation represented in CMBR
@@ -83,32 +201,203 @@ pub struct CmbrGame {
 pub struct CmbrVariation {
     pub starts_at: u16,
     pub moves: Vec<CmbrMv>,
-    /// The u16 denotes of which half move the comment is on
-    pub comments: Vec<(u16, String)>,
+    /// Ordered the same way as in the PGN
+    pub comments: Vec<CmbrComment>,
 }
 
-impl CmbrFile {
-    pub fn new(is_compressed: bool) -> Self {
-        if is_compressed {
-            panic!("Currently creating compressed CMBRs is not supported");
+/// A comment of a variation
+#[cfg_attr(feature = "bitcode", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CmbrComment {
+    /// The half move the comment is on, counted the same way as in `MoveId`
+    pub half_move: u16,
+    /// See `CmbrCommentKind`
+    pub kind: u8,
+    /// How many of the variations after the move come before the comment, like 1 for `{c}` in
+    /// `1. e4 (1. d4) {c}`
+    pub variations_before: u16,
+    pub text: String,
+}
+
+/// Adds a position to `positions`, unless it's already there, and returns its key. If the
+/// position at `key` is a different one, the keys after it are tried until a free one is found
+pub(crate) fn insert_position(
+    positions: &mut HashMap<PositionKey, CmbrPackedPosition>,
+    mut key: PositionKey,
+    position: &CmbrPackedPosition,
+) -> PositionKey {
+    loop {
+        match positions.get(&key) {
+            None => {
+                positions.insert(key, position.clone());
+                return key;
+            }
+            Some(stored) if stored == position => return key,
+            // A collision
+            Some(_) => key = key.wrapping_add(1),
         }
+    }
+}
+
+/// The key a position is tried at first in `CmbrFile::encountered_positions`
+pub(crate) fn position_hash<P: CmbrPosition>(board: &P) -> PositionKey {
+    return board.zobrist_hash::<Zobrist64>(EnPassantMode::Legal).0;
+}
+
+/// Moves the positions that a game reached (See `CmbrGame::encountered_positions`) from `from`
+/// to `into`. Their keys can change, since `into` can already have a different position with
+/// the same key. Positions that aren't in `from` keep their keys
+pub(crate) fn move_positions(
+    game_positions: &mut HashMap<MoveId, PositionKey>,
+    from: &HashMap<PositionKey, CmbrPackedPosition>,
+    into: &mut HashMap<PositionKey, CmbrPackedPosition>,
+) {
+    for key in game_positions.values_mut() {
+        if let Some(position) = from.get(key) {
+            *key = insert_position(into, *key, position);
+        }
+    }
+}
 
+impl CmbrFile {
+    pub fn new(is_compressed: bool) -> Self {
         return Self {
-            magic_bytes: "CMBR!",
             is_compressed,
             games: HashMap::with_capacity(16),
             encountered_positions: HashMap::with_capacity(1024),
         };
     }
+
+    /// Builds the position tables by replaying every game, in the order of the game ids. Used
+    /// for files converted without them. Positions that are already there are kept
+    pub fn build_position_tables(&mut self) -> Result<(), LibCmbrError> {
+        let mut game_ids: Vec<u32> = self.games.keys().copied().collect();
+        game_ids.sort_unstable();
+
+        for game_id in game_ids {
+            // SAFE: Safe. `game_id` is a key of `games`
+            let game = unsafe { self.games.get_mut(&game_id).unwrap_unchecked() };
+
+            with_position_type!(game.variant, P => {
+                game.build_positions::<P>(&mut self.encountered_positions)?
+            });
+        }
+
+        return Ok(());
+    }
+}
+
+/// Adds the positions reached in a variation and its sub variations to the position tables,
+/// with the same move ids as the ones `CmbrFile::from_ast` uses
+fn variation_positions<P: CmbrPosition>(
+    variations: &LiteMap<VariationPointerT, CmbrVariation>,
+    id: VariationPointerT,
+    mut board: P,
+    game_positions: &mut HashMap<MoveId, PositionKey>,
+    positions: &mut HashMap<PositionKey, CmbrPackedPosition>,
+) -> Result<(), LibCmbrError> {
+    let Some(variation) = variations.get(&id) else {
+        return Ok(());
+    };
+
+    let mut previous_board = board.clone();
+    let mut half_move = variation.starts_at;
+
+    let key = insert_position(
+        positions,
+        position_hash(&board),
+        &CmbrPackedPosition::from_position(&board),
+    );
+    let _ = game_positions.try_insert((id << 16) | half_move as u32, key);
+
+    for cmbr in &variation.moves {
+        match SanToCmbrMvConvertor::cmbr_to_shakmaty_move(&board, *cmbr)? {
+            DecodedCmbrMv::VariationPointer(pointer) => {
+                variation_positions(
+                    variations,
+                    pointer,
+                    previous_board.clone(),
+                    game_positions,
+                    positions,
+                )?;
+                continue;
+            }
+
+            DecodedCmbrMv::Nag(_) => continue,
+
+            DecodedCmbrMv::Move { shakmaty_move, .. } => {
+                previous_board = board.clone();
+                board.play_unchecked(&shakmaty_move);
+            }
+
+            DecodedCmbrMv::NullMove => {
+                previous_board = board.clone();
+                SanToCmbrMvConvertor::play_null_move(&mut board)?;
+            }
+        }
+
+        half_move += 1;
+
+        let key = insert_position(
+            positions,
+            position_hash(&board),
+            &CmbrPackedPosition::from_position(&board),
+        );
+        game_positions.insert((id << 16) | half_move as u32, key);
+    }
+
+    return Ok(());
 }
 
 impl CmbrGame {
+    pub fn castling_mode(&self) -> CastlingMode {
+        return if self.variant == CmbrVariant::Chess960 {
+            CastlingMode::Chess960
+        } else {
+            CastlingMode::Standard
+        };
+    }
+
+    /// Adds the positions the game reached to `positions`, and their keys to
+    /// `encountered_positions`. `P` should match `variant`
+    fn build_positions<P: CmbrPosition>(
+        &mut self,
+        positions: &mut HashMap<PositionKey, CmbrPackedPosition>,
+    ) -> Result<(), LibCmbrError> {
+        let board = self.starting_board::<P>()?;
+
+        return variation_positions(
+            &self.variations,
+            0,
+            board,
+            &mut self.encountered_positions,
+            positions,
+        );
+    }
+
+    /// The position the main variation starts from. `P` should match `variant`
+    pub fn starting_board<P: CmbrPosition>(&self) -> Result<P, LibCmbrError> {
+        let fen = match &self.starting_position {
+            None => return Ok(P::default()),
+            Some(fen) => fen,
+        };
+
+        return fen
+            .parse::<Fen>()
+            .ok()
+            .and_then(|fen| fen.into_position(self.castling_mode()).ok())
+            .ok_or(LibCmbrError::new(LibCmbrErrorType::InvalidFen));
+    }
+
     pub fn new() -> Self {
         return Self {
             headers: Vec::with_capacity(7),
             variations: LiteMap::with_capacity(1),
             result: 'u',
+            variant: CmbrVariant::Standard,
+            starting_position: None,
             encountered_positions: HashMap::with_capacity(79),
+            escape_comments: Vec::new(),
         };
     }
 }
@@ -123,3 +412,14 @@ impl CmbrVariation {
         };
     }
 }
+
+impl CmbrComment {
+    pub fn new(half_move: u16, kind: u8, text: &str) -> Self {
+        return Self {
+            half_move,
+            kind,
+            variations_before: 0,
+            text: text.to_owned(),
+        };
+    }
+}
@@ -3,12 +3,24 @@ mod cmbr_tests {
     #[allow(unused_imports)]
     use crate::pgn::{self, lex_pgn, Token};
     use crate::{
-        cmbr::{CmbrMv, SanToCmbrMvConvertor},
+        cmbr::{
+            insert_position, CmbrComment, CmbrCommentKind, CmbrConversionOptions, CmbrFile,
+            CmbrFileFlags, CmbrGame, CmbrGameBuilder, CmbrInvalidGamePolicy, CmbrMove, CmbrMv,
+            CmbrMvFlags, CmbrPackedPosition, CmbrReader, CmbrSectionCodec, CmbrSectionEntry,
+            CmbrSectionKind, CmbrVariant, CmbrVariation, CmbrWriter, DecodedCmbrMv, PositionKey,
+            SanToCmbrMvConvertor, CMBR_MAGIC_BYTES, CMBR_SECTION_ENTRY_SIZE,
+            DEFAULT_COMPRESSION_LEVEL, SAN_TABLE_BUCKET_SIZE,
+        },
+        error::{LibCmbrDiagnostic, LibCmbrError, LibCmbrErrorType},
         pgn::PgnToken,
     };
     use memmap2::Mmap;
     use project_root::get_project_root;
-    use shakmaty::Chess;
+    use shakmaty::fen::Fen;
+    use shakmaty::san::{San, Suffix};
+    use shakmaty::variant::Atomic;
+    use shakmaty::{CastlingMode, Chess, Color, Position, Role, Square};
+    use std::collections::{HashMap, HashSet};
     use std::fs::File;
 
     #[cfg(feature = "benchmark")]
@@ -73,6 +85,1378 @@ mod cmbr_tests {
         assert_eq!(expected_vec, cmbrs);
     }
 
+    #[test]
+    fn test_cmbr_san() {
+        for path in [
+            "data/promotion.pgn",
+            "data/fischer_spassky_1992.pgn",
+            "data/castling_and_en_passant.pgn",
+            "data/multiple_games.pgn",
+        ] {
+            let file_path = get_project_root().unwrap().join(path);
+            let file = File::open(file_path.clone());
+
+            if file.is_err() {
+                panic!(
+                    "[ERROR] {}. File path: {:?}",
+                    file.err().unwrap(),
+                    file_path
+                );
+            }
+
+            // SAFE: Safe
+            let file = unsafe { file.unwrap_unchecked() };
+            let mmap = unsafe { Mmap::map(&file) };
+
+            if mmap.is_err() {
+                panic!("[ERROR] {}", mmap.err().unwrap());
+            }
+
+            let mut mmap = mmap.unwrap();
+
+            let ast = pgn::parse_pgn(&mut mmap);
+            let mut convertor = SanToCmbrMvConvertor::new(0);
+
+            for game in ast {
+                let mut encoding_board = Chess::new();
+                let mut decoding_board = Chess::new();
+
+                for token in &game.variations.get(&0).unwrap().0 {
+                    if let PgnToken::Token(Token::Move(san)) = token {
+                        let cmbr = convertor.san_to_cmbr(&mut encoding_board, san).unwrap();
+                        let decoded =
+                            SanToCmbrMvConvertor::cmbr_to_san(&mut decoding_board, cmbr).unwrap();
+
+                        if let DecodedCmbrMv::Move {
+                            san: decoded_san,
+                            flags,
+                            ..
+                        } = decoded
+                        {
+                            assert_eq!(decoded_san.to_string().as_bytes(), *san);
+                            assert_eq!(flags, cmbr.to_u32() as u8);
+                        } else {
+                            panic!(
+                                "Expected {} to decode to a move",
+                                std::str::from_utf8(san).unwrap()
+                            );
+                        }
+
+                        assert_eq!(encoding_board, decoding_board);
+                    }
+                }
+            }
+        }
+
+        let mut board = Chess::new();
+        assert_eq!(
+            SanToCmbrMvConvertor::cmbr_to_san(&mut board, ((3 << 8) | 0b00001000).into()),
+            Ok(DecodedCmbrMv::Nag(3))
+        );
+        assert_eq!(
+            SanToCmbrMvConvertor::cmbr_to_san(&mut board, ((42 << 8) | 0b10000000).into()),
+            Ok(DecodedCmbrMv::VariationPointer(42))
+        );
+        assert_eq!(board, Chess::new());
+    }
+
+    #[test]
+    fn test_cmbr_move_round_trip() {
+        let mut valid_patterns = 0;
+
+        for bits in 0..(1u32 << 24) {
+            let cmbr: CmbrMv = bits.into();
+
+            if let Ok(cmbr_move) = CmbrMove::try_from(cmbr) {
+                assert_eq!(CmbrMv::from(cmbr_move), cmbr, "{cmbr_move:?}");
+                valid_patterns += 1;
+            }
+        }
+
+        // Normal moves: (2 pawns * 6 promotion options + 10 other pieces) * 64 * 63 squares * 2 capture * 3 suffixes
+        // Drops:        10 pieces (no kings) * 64 squares * 3 suffixes
+        // Castles:      4 castles * 3 suffixes
+        // Null moves:   2 colors
+        // NAGs:         256
+        // Pointers:     65536
+        assert_eq!(
+            valid_patterns,
+            22 * 64 * 63 * 2 * 3 + 10 * 64 * 3 + 4 * 3 + 2 + 256 + 65536
+        );
+
+        let promotion: CmbrMv = 0b111111110110000001110101.into();
+        let promotion = CmbrMove::try_from(promotion).unwrap();
+
+        assert_eq!(promotion.from(), Some(Square::G7));
+        assert_eq!(promotion.to(), Some(Square::H8));
+        assert_eq!(promotion.piece(), Some(Color::White.pawn()));
+        assert_eq!(promotion.promotion(), Some(Role::Queen));
+        assert!(promotion.is_capture());
+        assert!(promotion.is_check());
+        assert!(!promotion.is_mate());
+
+        let drop = CmbrMove::Drop {
+            piece: Color::Black.knight(),
+            to: Square::F2,
+            suffix: Some(Suffix::Check),
+        };
+        let encoded = CmbrMv::from(drop);
+
+        assert_eq!(
+            encoded.to_u32(),
+            (13 << 18) | (13 << 12) | (0b1001 << 8) | 1
+        );
+        assert_eq!(CmbrMove::try_from(encoded), Ok(drop));
+        assert!(drop.is_drop() && drop.is_move() && drop.from().is_none());
+
+        let null_move = CmbrMove::NullMove {
+            color: Color::Black,
+        };
+        let encoded = CmbrMv::from(null_move);
+
+        assert_eq!(encoded.to_u32(), 0b1101 << 8);
+        assert_eq!(CmbrMove::try_from(encoded), Ok(null_move));
+        assert!(null_move.is_null_move() && null_move.is_move() && null_move.piece().is_none());
+    }
+
+    #[test]
+    fn test_cmbr_to_pgn() {
+        let file_path = get_project_root()
+            .unwrap()
+            .join("data/with_varation_and_comments.pgn");
+        let file = File::open(file_path.clone());
+
+        if file.is_err() {
+            panic!(
+                "[ERROR] {}. File path: {:?}",
+                file.err().unwrap(),
+                file_path
+            );
+        }
+
+        // SAFE: Safe
+        let file = unsafe { file.unwrap_unchecked() };
+        let mmap = unsafe { Mmap::map(&file) };
+
+        if mmap.is_err() {
+            panic!("[ERROR] {}", mmap.err().unwrap());
+        }
+
+        let mut mmap = mmap.unwrap();
+
+        let ast = pgn::parse_pgn(&mut mmap);
+        let mut convertor = SanToCmbrMvConvertor::new(/* 16MB */ 16 * 1024 * 1024);
+        let cmbr_file = CmbrFile::from_ast(ast, &mut convertor, false).unwrap();
+
+        let cmbr_file = CmbrFile::deserialize(&cmbr_file.serialize()).unwrap();
+        let mut pgn = Vec::new();
+        cmbr_file.to_pgn(&mut pgn).unwrap();
+
+        let pgn_expected = "[E \"E\"]\n[S \"S\"]\n\n1. e4 (1. d4) (1. c4) 1... e5 2. Nf3 (2. Nc3 d5 (2... d6)) (2. d3) 2... Nc6 3.\nBc4 Bc5 4. O-O *\n\n[E \"E\"]\n[S \"S\"]\n\n1. e4 (1. d4) (1. c4) 1... e5 2. Nf3 (2. Nc3 d5 (2... d6)) (2. d3) { Comment }\n*\n\n";
+
+        assert_eq!(String::from_utf8(pgn).unwrap(), pgn_expected);
+    }
+
+    #[test]
+    fn test_deserialize_errors() {
+        let serialized = CmbrFile::new(false).serialize();
+
+        assert_eq!(CmbrFile::deserialize(&serialized), Ok(CmbrFile::new(false)));
+
+        let mut invalid_magic = serialized.clone();
+        invalid_magic[0] = b'P';
+        assert_eq!(
+            CmbrFile::deserialize(&invalid_magic).unwrap_err().kind(),
+            LibCmbrErrorType::InvalidMagicBytes
+        );
+
+        assert_eq!(
+            CmbrFile::deserialize(b"CMB").unwrap_err().kind(),
+            LibCmbrErrorType::TruncatedFile
+        );
+
+        assert_eq!(
+            CmbrFile::deserialize(&serialized[..serialized.len() - 1])
+                .unwrap_err()
+                .kind(),
+            LibCmbrErrorType::TruncatedFile
+        );
+
+        let mut corrupted = serialized.clone();
+        corrupted.push(0xff);
+        assert_eq!(
+            CmbrFile::deserialize(&corrupted).unwrap_err().kind(),
+            LibCmbrErrorType::CorruptedFile
+        );
+
+        // Sections can't share bytes, even if they end where the file does
+        for moves_offset in [0, 2] {
+            let mut overlapping = CMBR_MAGIC_BYTES.to_vec();
+            overlapping.extend_from_slice(&[0, 2]);
+            let offset = (overlapping.len() + 2 * CMBR_SECTION_ENTRY_SIZE) as u64;
+
+            for (kind, offset) in [
+                (CmbrSectionKind::Headers, offset),
+                (CmbrSectionKind::Moves, offset + moves_offset),
+            ] {
+                let entry = CmbrSectionEntry {
+                    kind,
+                    codec: CmbrSectionCodec::Raw,
+                    offset,
+                    length: 4,
+                };
+                overlapping.extend_from_slice(&entry.to_bytes());
+            }
+
+            overlapping.extend_from_slice(&[0; 6][..4 + moves_offset as usize]);
+            assert_eq!(
+                CmbrReader::new(&overlapping).err().map(|e| e.kind()),
+                Some(LibCmbrErrorType::CorruptedFile)
+            );
+        }
+    }
+
+    #[test]
+    fn test_compressed_round_trip() {
+        let file_path = get_project_root()
+            .unwrap()
+            .join("data/fischer_spassky_1992.pgn");
+        let file = File::open(file_path).unwrap();
+        let mut mmap = unsafe { Mmap::map(&file).unwrap() };
+
+        let ast = pgn::parse_pgn(&mut mmap);
+        let mut convertor = SanToCmbrMvConvertor::new(0);
+        let mut cmbr_file = CmbrFile::from_ast(ast, &mut convertor, true).unwrap();
+
+        let compressed = cmbr_file.serialize_with_compression_level(19);
+        let reader = CmbrReader::new(&compressed).unwrap();
+        assert!(reader.is_compressed());
+        assert_eq!(
+            reader.section(CmbrSectionKind::Moves).unwrap().codec,
+            CmbrSectionCodec::Zstd
+        );
+        assert_eq!(reader.read(), Ok(cmbr_file.clone()));
+
+        cmbr_file.is_compressed = false;
+        let uncompressed = cmbr_file.serialize();
+        assert!(!CmbrReader::new(&uncompressed).unwrap().is_compressed());
+        assert!(compressed.len() < uncompressed.len());
+
+        // Corrupting one section doesn't affect reading the others
+        let headers = reader.section(CmbrSectionKind::Headers).unwrap();
+        let mut corrupted = compressed.clone();
+        corrupted[headers.offset as usize] ^= 0xff;
+
+        let corrupted_reader = CmbrReader::new(&corrupted).unwrap();
+        assert_eq!(
+            corrupted_reader.read_headers().unwrap_err().kind(),
+            LibCmbrErrorType::CorruptedFile
+        );
+        assert_eq!(corrupted_reader.read_moves(), Ok(cmbr_file.moves_section()));
+        assert_eq!(
+            corrupted_reader.read_positions(),
+            Ok(cmbr_file.positions_section())
+        );
+
+        let mut unknown_flag = uncompressed.clone();
+        unknown_flag[CMBR_MAGIC_BYTES.len()] |= 1 << 7;
+        assert_eq!(
+            CmbrFile::deserialize(&unknown_flag).unwrap_err().kind(),
+            LibCmbrErrorType::CorruptedFile
+        );
+    }
+
+    #[test]
+    fn test_from_ast_multithreaded() {
+        let file_path = get_project_root().unwrap().join("data/multiple_games.pgn");
+        let file = File::open(file_path).unwrap();
+        let mut mmap = unsafe { Mmap::map(&file).unwrap() };
+
+        let ast = pgn::parse_pgn(&mut mmap);
+        let mut convertor = SanToCmbrMvConvertor::new(0);
+        let expected = CmbrFile::from_ast(ast.clone(), &mut convertor, false).unwrap();
+        assert_eq!(expected.games.len(), 6);
+
+        for thread_count in [1, 2, 3, 8] {
+            let cmbr_file =
+                CmbrFile::from_ast_multithreaded(ast.clone(), 0, false, thread_count).unwrap();
+
+            assert_eq!(cmbr_file, expected, "thread count: {thread_count}");
+        }
+    }
+
+    #[test]
+    fn test_streaming_writer() {
+        let file_path = get_project_root().unwrap().join("data/multiple_games.pgn");
+        let file = File::open(file_path).unwrap();
+        let mut mmap = unsafe { Mmap::map(&file).unwrap() };
+        let pgn = mmap.to_vec();
+
+        let ast = pgn::parse_pgn(&mut mmap);
+        let mut convertor = SanToCmbrMvConvertor::new(0);
+        let expected = CmbrFile::from_ast(ast, &mut convertor, false).unwrap();
+
+        // With a memory limit of 1 byte, every game is written in its own batch
+        for (memory_limit, thread_count, batches) in [(0, 1, 1), (1, 1, 6), (0, 3, 1), (1, 3, 6)] {
+            for is_compressed in [false, true] {
+                let mut writer = CmbrWriter::new(
+                    Vec::new(),
+                    is_compressed,
+                    DEFAULT_COMPRESSION_LEVEL,
+                    memory_limit,
+                )
+                .unwrap();
+
+                let games = pgn::iter_pgn(&pgn);
+                let options = CmbrConversionOptions {
+                    policy: CmbrInvalidGamePolicy::Skip,
+                    ..Default::default()
+                };
+
+                if thread_count == 1 {
+                    writer.convert_games(games, &mut convertor, options, |_| {})
+                } else {
+                    writer.convert_games_multithreaded(games, 0, thread_count, options, |_| {})
+                }
+                .unwrap();
+
+                assert_eq!(writer.game_count(), 6);
+                let bytes = writer.finish().unwrap();
+
+                let reader = CmbrReader::new(&bytes).unwrap();
+                assert!(reader.is_streamed());
+                assert_eq!(reader.sections(CmbrSectionKind::Headers).count(), batches);
+                assert_eq!(reader.sections(CmbrSectionKind::Positions).count(), batches);
+
+                let mut streamed = reader.read().unwrap();
+                assert_eq!(streamed.is_compressed, is_compressed);
+
+                streamed.is_compressed = false;
+                assert_eq!(streamed, expected, "memory limit: {memory_limit}");
+
+                // The number of entries of the section table is at the very end
+                assert!(CmbrReader::new(&bytes[..bytes.len() - 1]).is_err());
+            }
+        }
+
+        let empty = CmbrWriter::new(Vec::new(), false, DEFAULT_COMPRESSION_LEVEL, 0)
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(CmbrFile::deserialize(&empty).unwrap(), CmbrFile::new(false));
+    }
+
+    #[test]
+    fn test_position_tables() {
+        for file_name in [
+            "with_varation_and_comments.pgn",
+            "multiple_games.pgn",
+            "from_position.pgn",
+            "variants.pgn",
+        ] {
+            let file_path = get_project_root().unwrap().join("data").join(file_name);
+            let file = File::open(file_path).unwrap();
+            let mmap = unsafe { Mmap::map(&file).unwrap() };
+            let ast: Vec<_> = pgn::iter_pgn(&mmap).collect();
+
+            let mut convertor = SanToCmbrMvConvertor::new(0);
+            let with_positions = CmbrFile::from_ast(ast.clone(), &mut convertor, false).unwrap();
+            let without_positions = CmbrFile::from_ast_with_diagnostics(
+                ast,
+                &mut convertor,
+                CmbrConversionOptions {
+                    with_positions: false,
+                    policy: CmbrInvalidGamePolicy::Abort,
+                    ..Default::default()
+                },
+                |_| {},
+            )
+            .unwrap();
+
+            assert!(without_positions.encountered_positions.is_empty());
+            assert!(without_positions
+                .games
+                .values()
+                .all(|game| game.encountered_positions.is_empty()));
+
+            let mut expected = Vec::new();
+            with_positions.to_pgn(&mut expected).unwrap();
+            let mut output = Vec::new();
+            without_positions.to_pgn(&mut output).unwrap();
+            assert_eq!(output, expected, "file: {file_name}");
+
+            let bytes = without_positions.serialize();
+            let reader = CmbrReader::new(&bytes).unwrap();
+            assert!(reader.section(CmbrSectionKind::Positions).is_none());
+            assert_eq!(reader.read(), Ok(without_positions.clone()));
+
+            // Rebuilding the tables by replay gives the ones built while converting
+            let mut rebuilt = without_positions;
+            rebuilt.build_position_tables().unwrap();
+
+            for (id, game) in &rebuilt.games {
+                assert_eq!(
+                    game.encountered_positions, with_positions.games[id].encountered_positions,
+                    "file: {file_name}, game: {id}"
+                );
+            }
+
+            for (key, position) in &rebuilt.encountered_positions {
+                assert_eq!(
+                    with_positions.encountered_positions.get(key),
+                    Some(position)
+                );
+            }
+        }
+
+        let file_path = get_project_root().unwrap().join("data/multiple_games.pgn");
+        let pgn = std::fs::read(file_path).unwrap();
+        let mut writer = CmbrWriter::new(Vec::new(), false, DEFAULT_COMPRESSION_LEVEL, 1).unwrap();
+        let mut convertor = SanToCmbrMvConvertor::new(0);
+
+        writer
+            .convert_games(
+                pgn::iter_pgn(&pgn),
+                &mut convertor,
+                CmbrConversionOptions {
+                    with_positions: false,
+                    policy: CmbrInvalidGamePolicy::Abort,
+                    ..Default::default()
+                },
+                |_| {},
+            )
+            .unwrap();
+
+        let bytes = writer.finish().unwrap();
+        let reader = CmbrReader::new(&bytes).unwrap();
+        assert_eq!(reader.sections(CmbrSectionKind::Headers).count(), 6);
+        assert_eq!(reader.sections(CmbrSectionKind::Positions).count(), 0);
+        assert!(reader.read().unwrap().encountered_positions.is_empty());
+    }
+
+    #[test]
+    fn test_position_keys() {
+        let start_fen = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq -";
+        let start = CmbrPackedPosition::from_fen(start_fen).unwrap();
+        let after_e4 =
+            CmbrPackedPosition::from_fen("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq -")
+                .unwrap();
+
+        let mut positions = HashMap::new();
+        assert_eq!(insert_position(&mut positions, u64::MAX, &start), u64::MAX);
+        // A different position with the same key gets the next free one
+        assert_eq!(insert_position(&mut positions, u64::MAX, &after_e4), 0);
+        assert_eq!(insert_position(&mut positions, u64::MAX, &after_e4), 0);
+        assert_eq!(positions.len(), 2);
+
+        // Batches of a streamed file can use the same key for different positions
+        let mut writer = CmbrWriter::new(Vec::new(), false, DEFAULT_COMPRESSION_LEVEL, 1).unwrap();
+
+        for position in [&start, &after_e4] {
+            let mut game = CmbrGame::new();
+            game.encountered_positions.insert(0, 7);
+            writer
+                .push_game(game, &HashMap::from([(7, position.clone())]))
+                .unwrap();
+        }
+
+        let bytes = writer.finish().unwrap();
+        let reader = CmbrReader::new(&bytes).unwrap();
+        assert_ne!(reader.flags() & CmbrFileFlags::WidePositionKeys, 0);
+        assert_ne!(reader.flags() & CmbrFileFlags::PackedPositions, 0);
+
+        let file = reader.read().unwrap();
+        for (id, position) in [&start, &after_e4].into_iter().enumerate() {
+            let key = file.games[&(id as u32)].encountered_positions[&0];
+            assert_eq!(&file.encountered_positions[&key], position);
+        }
+
+        // Files without every flag of this version are rejected
+        let mut bytes = CmbrFile::new(false).serialize();
+        bytes[CMBR_MAGIC_BYTES.len()] &= !CmbrFileFlags::PackedPositions;
+        assert_eq!(
+            CmbrReader::new(&bytes).unwrap_err().kind(),
+            LibCmbrErrorType::CorruptedFile
+        );
+    }
+
+    #[test]
+    fn test_packed_positions() {
+        let fens = [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 1",
+            "4k3/8/8/8/8/8/8/4K2R w K - 0 1",
+            // Chess960 castling rights, which aren't on the corners
+            "bnrbkrqn/pppppppp/8/8/8/8/PPPPPPPP/BNRBKRQN w FCfc - 0 1",
+            // Pockets and promoted pieces
+            "rnbqkb1r/pppppppp/5n2/8/8/8/PPPPPPPP/RNBQKBQ~R[Pnn] w KQkq - 0 1",
+            // Remaining checks
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 2+3 0 1",
+            "8/8/8/8/8/8/8/8 b - - 0 1",
+        ];
+
+        for fen in fens {
+            let setup = Fen::into_setup(fen.parse::<Fen>().unwrap());
+            let packed = CmbrPackedPosition::from_setup(&setup);
+
+            assert_eq!(packed.to_setup(), Ok(setup), "{fen}");
+        }
+
+        // The occupancy, 16 bytes of pieces, the flags and the castling rights
+        let start = CmbrPackedPosition::from_position(&Chess::default());
+        assert_eq!(start.0.len(), 8 + 16 + 1 + 1);
+        assert_eq!(
+            start.to_position::<Chess>(CastlingMode::Standard),
+            Ok(Chess::default())
+        );
+
+        let mut truncated = start.clone();
+        truncated.0.pop();
+        let mut trailing = start.clone();
+        trailing.0.push(0);
+        let mut invalid_piece = start.clone();
+        invalid_piece.0[8] = 0b0111;
+        let mut unknown_flag = start.clone();
+        unknown_flag.0[8 + 16] |= 1 << 7;
+
+        for corrupted in [truncated, trailing, invalid_piece, unknown_flag] {
+            assert_eq!(
+                corrupted.to_setup().unwrap_err().kind(),
+                LibCmbrErrorType::CorruptedFile
+            );
+        }
+    }
+
+    #[test]
+    fn test_starting_position() {
+        let file_path = get_project_root().unwrap().join("data/from_position.pgn");
+        let file = File::open(file_path).unwrap();
+        let mut mmap = unsafe { Mmap::map(&file).unwrap() };
+
+        let ast = pgn::parse_pgn(&mut mmap);
+        let mut convertor = SanToCmbrMvConvertor::new(0);
+        let cmbr_file = CmbrFile::from_ast(ast, &mut convertor, false).unwrap();
+
+        let game = &cmbr_file.games[&0];
+        assert_eq!(
+            game.starting_position.as_deref(),
+            Some("r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3")
+        );
+        assert_eq!(game.variations[&0].starts_at, 4);
+        assert_eq!(game.variations[&0].moves.len(), 5);
+
+        let game = &cmbr_file.games[&1];
+        assert_eq!(game.variations[&0].starts_at, 1);
+        assert_eq!(game.variations[&0].moves.len(), 4);
+
+        let deserialized = CmbrFile::deserialize(&cmbr_file.serialize()).unwrap();
+        assert_eq!(deserialized, cmbr_file);
+
+        let mut pgn = Vec::new();
+        deserialized.to_pgn(&mut pgn).unwrap();
+
+        assert_eq!(
+            String::from_utf8(pgn).unwrap(),
+            "[Event \"From position\"]\n\
+             [SetUp \"1\"]\n\
+             [FEN \"r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3\"]\n\n\
+             3. Bb5 a6 (3... Nf6 4. O-O) 4. Ba4 Nf6 *\n\n\
+             [Event \"Black to move\"]\n\
+             [SetUp \"1\"]\n\
+             [FEN \"rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1\"]\n\n\
+             1... c5 2. Nf3 (2. c3 d5) 2... d6 *\n\n"
+        );
+
+        // Half moves are counted in a u16
+        let pgn = "[SetUp \"1\"]\n\
+                   [FEN \"rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 40000\"]\n\n\
+                   40000. e4 *\n\n";
+        let ast: Vec<_> = pgn::iter_pgn(pgn.as_bytes()).collect();
+        let mut diagnostics = Vec::new();
+        CmbrFile::from_ast_with_diagnostics(
+            ast,
+            &mut convertor,
+            CmbrConversionOptions {
+                policy: CmbrInvalidGamePolicy::Skip,
+                ..Default::default()
+            },
+            |d| diagnostics.push(d.kind),
+        )
+        .unwrap();
+        assert_eq!(diagnostics, [LibCmbrErrorType::InvalidFen]);
+
+        // Move numbers have to fit in the half move counter, and the main variation has to start
+        // from the starting position
+        let pgn = "0. e4 *\n\n\
+                   1. e4 e5 32768. Nf3 *\n\n\
+                   [SetUp \"1\"]\n\
+                   [FEN \"r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3\"]\n\n\
+                   1. Bb5 *\n\n";
+        let ast: Vec<_> = pgn::iter_pgn(pgn.as_bytes()).collect();
+        let mut diagnostics = Vec::new();
+        CmbrFile::from_ast_with_diagnostics(
+            ast,
+            &mut convertor,
+            CmbrConversionOptions {
+                policy: CmbrInvalidGamePolicy::Skip,
+                ..Default::default()
+            },
+            |d| diagnostics.push((d.kind, d.half_move)),
+        )
+        .unwrap();
+        assert_eq!(
+            diagnostics,
+            [
+                (LibCmbrErrorType::InvalidMoveNumber, 0),
+                (LibCmbrErrorType::InvalidMoveNumber, 2),
+                (LibCmbrErrorType::InvalidMoveNumber, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_chess960() {
+        let file_path = get_project_root().unwrap().join("data/chess960.pgn");
+        let file = File::open(file_path).unwrap();
+        let mut mmap = unsafe { Mmap::map(&file).unwrap() };
+
+        let ast = pgn::parse_pgn(&mut mmap);
+        let mut convertor = SanToCmbrMvConvertor::new(0);
+        let cmbr_file = CmbrFile::from_ast(ast, &mut convertor, false).unwrap();
+
+        let game = &cmbr_file.games[&0];
+        assert_eq!(game.variant, CmbrVariant::Chess960);
+        assert_eq!(game.variations.len(), 3);
+        assert!(game
+            .variations
+            .values()
+            .all(|variation| variation.moves.len() >= 2));
+
+        let deserialized = CmbrFile::deserialize(&cmbr_file.serialize()).unwrap();
+        let mut pgn = Vec::new();
+        deserialized.to_pgn(&mut pgn).unwrap();
+
+        assert_eq!(
+            String::from_utf8(pgn).unwrap(),
+            "[Event \"Chess960\"]\n\
+             [Variant \"Chess960\"]\n\
+             [SetUp \"1\"]\n\
+             [FEN \"nrbkqbrn/pppppppp/8/8/8/8/PPPPPPPP/NRBKQBRN w KQkq - 0 1\"]\n\n\
+             1. b3 g6 2. Bb2 Bg7 3. O-O-O (3. e3 e6) 3... e6 4. e3 Qe7 5. Qe2 O-O (5... d6\n\
+             6. Kb1 O-O) *\n\n"
+        );
+    }
+
+    #[test]
+    fn test_crazyhouse() {
+        let file_path = get_project_root().unwrap().join("data/crazyhouse.pgn");
+        let file = File::open(file_path).unwrap();
+        let mut mmap = unsafe { Mmap::map(&file).unwrap() };
+
+        let ast = pgn::parse_pgn(&mut mmap);
+        let mut convertor = SanToCmbrMvConvertor::new(0);
+        let cmbr_file = CmbrFile::from_ast(ast, &mut convertor, false).unwrap();
+
+        let game = &cmbr_file.games[&0];
+        assert_eq!(game.variant, CmbrVariant::Crazyhouse);
+
+        let drops = game
+            .variations
+            .values()
+            .flat_map(|variation| &variation.moves)
+            .filter(|cmbr| CmbrMove::try_from(**cmbr).is_ok_and(|m| m.is_drop()))
+            .count();
+        assert_eq!(drops, 3);
+
+        let deserialized = CmbrFile::deserialize(&cmbr_file.serialize()).unwrap();
+        let mut pgn = Vec::new();
+        deserialized.to_pgn(&mut pgn).unwrap();
+
+        assert_eq!(
+            String::from_utf8(pgn).unwrap(),
+            "[Event \"Crazyhouse\"]\n\
+             [Variant \"Crazyhouse\"]\n\n\
+             1. e4 d5 2. exd5 Qxd5 3. Nc3 Qa5 4. Nf3 Nf6 5. Bc4 P@e4 (5... e6 6. P@e5 Nd5 7.\n\
+             Nxd5) 6. Nxe4 Nxe4 7. O-O N@c3 *\n\n"
+        );
+    }
+
+    #[test]
+    fn test_variants() {
+        let file_path = get_project_root().unwrap().join("data/variants.pgn");
+        let file = File::open(file_path.clone()).unwrap();
+        let mut mmap = unsafe { Mmap::map(&file).unwrap() };
+
+        let ast = pgn::parse_pgn(&mut mmap);
+        let mut convertor = SanToCmbrMvConvertor::new(0);
+        let cmbr_file = CmbrFile::from_ast(ast, &mut convertor, false).unwrap();
+
+        let variants = [
+            CmbrVariant::Atomic,
+            CmbrVariant::Antichess,
+            CmbrVariant::KingOfTheHill,
+            CmbrVariant::ThreeCheck,
+            CmbrVariant::Horde,
+            CmbrVariant::RacingKings,
+        ];
+        let move_counts = [5, 6, 7, 6, 4, 4];
+
+        for (i, (variant, move_count)) in variants.iter().zip(move_counts).enumerate() {
+            let game = &cmbr_file.games[&(i as u32)];
+
+            assert_eq!(game.variant, *variant);
+            assert_eq!(game.variations[&0].moves.len(), move_count);
+        }
+
+        let deserialized = CmbrFile::deserialize(&cmbr_file.serialize()).unwrap();
+        let mut pgn = Vec::new();
+        deserialized.to_pgn(&mut pgn).unwrap();
+
+        let expected = std::fs::read_to_string(file_path).unwrap();
+        assert_eq!(String::from_utf8(pgn).unwrap(), expected + "\n");
+
+        let king_promotion = CmbrMove::Normal {
+            piece: Color::White.pawn(),
+            from: Square::E7,
+            to: Square::E8,
+            capture: false,
+            promotion: Some(Role::King),
+            suffix: None,
+        };
+        let encoded = CmbrMv::from(king_promotion);
+
+        assert_eq!(encoded.to_u32() as u8, CmbrMvFlags::FlagPromotesKing);
+        assert_eq!(CmbrMove::try_from(encoded), Ok(king_promotion));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let file_path = get_project_root().unwrap().join("data/invalid_games.pgn");
+        let file = File::open(file_path).unwrap();
+        let mut mmap = unsafe { Mmap::map(&file).unwrap() };
+
+        let ast = pgn::parse_pgn(&mut mmap);
+        let mut convertor = SanToCmbrMvConvertor::new(0);
+        let mut diagnostics = Vec::new();
+        let cmbr_file = CmbrFile::from_ast_with_diagnostics(
+            ast,
+            &mut convertor,
+            CmbrConversionOptions::default(),
+            |d| diagnostics.push(d),
+        )
+        .unwrap();
+
+        let kinds: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.kind, d.game, d.half_move))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (LibCmbrErrorType::InvalidFen, 0, 0),
+                (LibCmbrErrorType::InvalidNag, 1, 3),
+                (LibCmbrErrorType::InvalidNag, 1, 4),
+                (LibCmbrErrorType::InvalidSan, 1, 4),
+            ]
+        );
+
+        let invalid_san = &diagnostics[3];
+        assert_eq!(invalid_san.token.as_deref(), Some("Ke3"));
+        assert_eq!(
+            invalid_san.fen.as_deref(),
+            Some("r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq -")
+        );
+
+        assert_eq!(cmbr_file.games.len(), 3);
+        assert_eq!(cmbr_file.games[&2].variations[&0].moves.len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_game_policy() {
+        let file_path = get_project_root().unwrap().join("data/invalid_games.pgn");
+
+        let convert = |policy, thread_count| {
+            let file = File::open(&file_path).unwrap();
+            let mut mmap = unsafe { Mmap::map(&file).unwrap() };
+            let ast = pgn::parse_pgn(&mut mmap);
+
+            return CmbrFile::from_ast_multithreaded_with_diagnostics(
+                ast,
+                0,
+                thread_count,
+                CmbrConversionOptions {
+                    policy,
+                    ..Default::default()
+                },
+                |_| {},
+            );
+        };
+
+        let skipped = convert(CmbrInvalidGamePolicy::Skip, 1).unwrap();
+        assert_eq!(skipped.games.len(), 1);
+        assert_eq!(skipped.games[&0].variations[&0].moves.len(), 2);
+
+        // Positions that were only reached by the skipped games are left out
+        let reached: HashSet<PositionKey> = skipped
+            .games
+            .values()
+            .flat_map(|game| game.encountered_positions.values().copied())
+            .collect();
+        let stored: HashSet<PositionKey> = skipped.encountered_positions.keys().copied().collect();
+        assert_eq!(reached, stored);
+
+        assert_eq!(convert(CmbrInvalidGamePolicy::Skip, 3).unwrap(), skipped);
+
+        let kept = convert(CmbrInvalidGamePolicy::KeepTruncated, 2).unwrap();
+        assert_eq!(kept.games.len(), 3);
+        assert!(kept.games[&0].variations.is_empty());
+        assert_eq!(kept.games[&1].variations[&0].moves.len(), 4);
+        assert!(kept.encountered_positions.len() > skipped.encountered_positions.len());
+
+        for thread_count in [1, 3] {
+            let error = convert(CmbrInvalidGamePolicy::Abort, thread_count).unwrap_err();
+            let error = error.downcast_ref::<LibCmbrDiagnostic>().unwrap();
+
+            assert_eq!(error.kind, LibCmbrErrorType::InvalidFen);
+            assert_eq!(error.game, 0);
+        }
+    }
+
+    #[test]
+    fn test_variation_tree() {
+        let convert = |pgn: &str, policy| {
+            let ast: Vec<_> = pgn::iter_pgn(pgn.as_bytes()).collect();
+            let mut convertor = SanToCmbrMvConvertor::new(0);
+
+            return CmbrFile::from_ast_with_diagnostics(
+                ast,
+                &mut convertor,
+                CmbrConversionOptions {
+                    policy,
+                    ..Default::default()
+                },
+                |_| {},
+            );
+        };
+
+        // Alternatives to the first move of a variation start from the same position as it
+        let pgn = "1. e4 (1. d4 (1. c4 (1. Nf3))) e5 *\n\n";
+        let cmbr_file = convert(pgn, CmbrInvalidGamePolicy::Abort).unwrap();
+        let game = &cmbr_file.games[&0];
+
+        let ids: Vec<_> = game.variations.keys().copied().collect();
+        assert_eq!(ids, [0, 1, 2, 3]);
+
+        let mut output = Vec::new();
+        cmbr_file.to_pgn(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\n1. e4 (1. d4 (1. c4 (1. Nf3))) 1... e5 *\n\n"
+        );
+
+        // NAGs and comments can come between a move and its alternatives
+        let pgn = "1. e4 e5 2. Nf3 $1 {Best} (2. f4 exf4) 2... Nc6 *\n\n";
+        let cmbr_file = convert(pgn, CmbrInvalidGamePolicy::Abort).unwrap();
+
+        let mut output = Vec::new();
+        cmbr_file.to_pgn(&mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), format!("\n{pgn}"));
+
+        // Comments keep their place around the alternatives
+        let pgn = "1. e4 {before} (1. d4) {between} (1. c4) {after} 1... e5 *\n\n";
+        let cmbr_file = convert(pgn, CmbrInvalidGamePolicy::Abort).unwrap();
+        assert_eq!(
+            cmbr_file.games[&0].variations[&0]
+                .comments
+                .iter()
+                .map(|comment| comment.variations_before)
+                .collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+
+        let mut output = Vec::new();
+        cmbr_file.to_pgn(&mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), format!("\n{pgn}"));
+
+        // Deep nesting used to overflow the variation ids
+        let first_moves = [
+            "d4", "c4", "Nf3", "b3", "g3", "f4", "Nc3", "b4", "e3", "d3", "c3", "a3", "h3", "g4",
+            "a4", "h4", "Nh3", "Na3", "f3",
+        ];
+        let mut pgn = String::from("1. e4 ");
+        for first_move in first_moves {
+            pgn += &format!("(1. {first_move} ");
+        }
+        pgn += &")".repeat(first_moves.len());
+        pgn += " *\n\n";
+
+        let cmbr_file = convert(&pgn, CmbrInvalidGamePolicy::Abort).unwrap();
+        assert_eq!(cmbr_file.games[&0].variations.len(), first_moves.len() + 1);
+
+        let pgn = format!("1. e4 {} *\n\n", "(1. d4) ".repeat(u16::MAX as usize + 1));
+        let error = convert(&pgn, CmbrInvalidGamePolicy::Abort).unwrap_err();
+        let error = error.downcast_ref::<LibCmbrDiagnostic>().unwrap();
+        assert_eq!(error.kind, LibCmbrErrorType::TooManyVariations);
+    }
+
+    #[test]
+    fn test_comment_kinds() {
+        let pgn = "%before the tags\n[Event \"E\"]\n%after the tags\n\n{brace} 1. e4 ; line } comment\ne5 (1... c5 ;in a variation\n) 2. Nf3\n%after Nf3\n2... Nc6 *\n\n";
+        let ast: Vec<_> = pgn::iter_pgn(pgn.as_bytes()).collect();
+        let mut convertor = SanToCmbrMvConvertor::new(0);
+        let cmbr_file = CmbrFile::from_ast(ast, &mut convertor, false).unwrap();
+        let game = &cmbr_file.games[&0];
+
+        assert_eq!(game.escape_comments, ["before the tags"]);
+        assert_eq!(
+            game.variations[&0].comments,
+            [
+                CmbrComment::new(0, CmbrCommentKind::Escape, "after the tags"),
+                CmbrComment::new(0, CmbrCommentKind::Brace, "brace"),
+                CmbrComment::new(1, CmbrCommentKind::Line, " line } comment"),
+                CmbrComment::new(3, CmbrCommentKind::Escape, "after Nf3"),
+            ]
+        );
+
+        let mut output = Vec::new();
+        cmbr_file.to_pgn(&mut output).unwrap();
+        let exported = String::from_utf8(output).unwrap();
+        let ast: Vec<_> = pgn::iter_pgn(exported.as_bytes()).collect();
+        let reconverted = CmbrFile::from_ast(ast, &mut convertor, false).unwrap();
+        assert_eq!(reconverted.games, cmbr_file.games);
+        assert_eq!(
+            exported,
+            "%before the tags\n[Event \"E\"]\n\n%after the tags\n{brace} 1. e4 ; line } comment\n1... e5 (1... c5 ;in a variation\n) 2. Nf3\n%after Nf3\n2... Nc6 *\n\n"
+        );
+
+        let bytes = cmbr_file.serialize();
+        assert_ne!(
+            CmbrReader::new(&bytes).unwrap().flags() & CmbrFileFlags::CommentKinds,
+            0
+        );
+        assert_eq!(CmbrFile::deserialize(&bytes), Ok(cmbr_file.clone()));
+
+        // A `}` can't be written in a brace comment
+        let mut brace = cmbr_file.clone();
+        brace
+            .games
+            .get_mut(&0)
+            .unwrap()
+            .variations
+            .get_mut(&0)
+            .unwrap()
+            .comments[2]
+            .kind = CmbrCommentKind::Brace;
+
+        let mut output = Vec::new();
+        brace.to_pgn(&mut output).unwrap();
+        let exported = String::from_utf8(output).unwrap();
+        assert!(exported.contains("1. e4 ; line } comment\n1... e5"));
+
+        let ast: Vec<_> = pgn::iter_pgn(exported.as_bytes()).collect();
+        let reconverted = CmbrFile::from_ast(ast, &mut convertor, false).unwrap();
+        assert_eq!(
+            reconverted.games[&0].variations[&0].moves,
+            brace.games[&0].variations[&0].moves
+        );
+    }
+
+    #[test]
+    fn test_null_moves() {
+        let convert = |pgn: &str| {
+            let ast: Vec<_> = pgn::iter_pgn(pgn.as_bytes()).collect();
+            let mut convertor = SanToCmbrMvConvertor::new(0);
+            let mut diagnostics = Vec::new();
+
+            let cmbr_file = CmbrFile::from_ast_with_diagnostics(
+                ast,
+                &mut convertor,
+                CmbrConversionOptions {
+                    policy: CmbrInvalidGamePolicy::KeepTruncated,
+                    ..Default::default()
+                },
+                |d| diagnostics.push(d),
+            )
+            .unwrap();
+
+            return (cmbr_file, diagnostics);
+        };
+
+        let (cmbr_file, diagnostics) = convert("1. e4 -- 2. d4 Z0 (2... Nf6 3. e5) 3. c4 e5 *\n\n");
+        assert!(diagnostics.is_empty());
+
+        let main_variation = &cmbr_file.games[&0].variations[&0];
+        assert_eq!(
+            CmbrMove::try_from(main_variation.moves[1]),
+            Ok(CmbrMove::NullMove {
+                color: Color::Black
+            })
+        );
+
+        let mut output = Vec::new();
+        cmbr_file.to_pgn(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\n1. e4 -- 2. d4 -- (2... Nf6 3. e5) 3. c4 e5 *\n\n"
+        );
+
+        let mut rebuilt = cmbr_file.clone();
+        rebuilt.encountered_positions.clear();
+        rebuilt
+            .games
+            .get_mut(&0)
+            .unwrap()
+            .encountered_positions
+            .clear();
+        rebuilt.build_position_tables().unwrap();
+        assert_eq!(
+            rebuilt.games[&0].encountered_positions,
+            cmbr_file.games[&0].encountered_positions
+        );
+
+        // The side to move can't pass while in check
+        let (cmbr_file, diagnostics) = convert("1. e4 f5 2. Qh5+ -- *\n\n");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].kind, LibCmbrErrorType::InvalidSan);
+        assert_eq!(cmbr_file.games[&0].variations[&0].moves.len(), 3);
+
+        let mut board = Chess::default();
+        let black_null_move = CmbrMove::NullMove {
+            color: Color::Black,
+        };
+        assert_eq!(
+            SanToCmbrMvConvertor::cmbr_to_san(&mut board, black_null_move.into()),
+            Err(LibCmbrError::new(LibCmbrErrorType::IllegalCmbrMv))
+        );
+
+        let white_null_move = CmbrMove::NullMove {
+            color: Color::White,
+        };
+        assert_eq!(
+            SanToCmbrMvConvertor::cmbr_to_san(&mut board, white_null_move.into()),
+            Ok(DecodedCmbrMv::NullMove)
+        );
+        assert_eq!(board.turn(), Color::Black);
+    }
+
+    #[test]
+    fn test_nags() {
+        let pgn = "1. e4! e5?! 2. Nf3 $14 Nc6 $300 3. Bb5!!! a6?? *\n\n";
+        let ast: Vec<_> = pgn::iter_pgn(pgn.as_bytes()).collect();
+        let mut convertor = SanToCmbrMvConvertor::new(0);
+        let mut diagnostics = Vec::new();
+
+        let cmbr_file = CmbrFile::from_ast_with_diagnostics(
+            ast,
+            &mut convertor,
+            CmbrConversionOptions {
+                policy: CmbrInvalidGamePolicy::Abort,
+                ..Default::default()
+            },
+            |d| diagnostics.push(d),
+        )
+        .unwrap();
+
+        // NAGs above 255 and unknown glyphs are left out
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics
+            .iter()
+            .all(|d| d.kind == LibCmbrErrorType::InvalidNag));
+
+        let main_variation = &cmbr_file.games[&0].variations[&0];
+        assert_eq!(cmbr_file.games[&0].variations.len(), 1);
+        let nags: Vec<_> = main_variation
+            .moves
+            .iter()
+            .filter_map(|&mv| CmbrMove::try_from(mv).unwrap().nag())
+            .collect();
+        assert_eq!(nags, [1, 6, 14, 4]);
+
+        let mut output = Vec::new();
+        cmbr_file.to_pgn(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\n1. e4 $1 e5 $6 2. Nf3 $14 Nc6 3. Bb5 a6 $4 *\n\n"
+        );
+    }
+
+    #[test]
+    fn test_replay() {
+        let pgn = "1. e4 {best} $1 (1. d4 d5 (1... Nf6 2. c4)) 1... e5 2. Nf3 -- *\n\n";
+        let ast: Vec<_> = pgn::iter_pgn(pgn.as_bytes()).collect();
+        let mut convertor = SanToCmbrMvConvertor::new(0);
+        let cmbr_file = CmbrFile::from_ast(ast, &mut convertor, false).unwrap();
+        let game = &cmbr_file.games[&0];
+
+        let steps: Vec<_> = game
+            .replay::<Chess>()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            steps.iter().map(|step| step.ply).collect::<Vec<_>>(),
+            [1, 2, 3, 4]
+        );
+        assert_eq!(steps[0].shakmaty_move.as_ref().unwrap().to(), Square::E4);
+        assert_eq!(
+            steps[0].comments,
+            [&CmbrComment::new(1, CmbrCommentKind::Brace, "best")]
+        );
+        assert_eq!(steps[0].nags, [1]);
+        assert_eq!(steps[0].variations, [1]);
+        assert!(steps[3].shakmaty_move.is_none());
+        assert_eq!(steps[3].board.turn(), Color::White);
+        assert_eq!(
+            CmbrPackedPosition::from_position(&steps[3].board),
+            game.encountered_positions
+                .get(&4)
+                .map(|key| cmbr_file.encountered_positions[key].clone())
+                .unwrap()
+        );
+
+        let mut cursor = game.cursor::<Chess>().unwrap();
+        assert_eq!(
+            cursor.enter(1),
+            Err(LibCmbrError::new(LibCmbrErrorType::NotAnAlternative))
+        );
+        cursor.next_step().unwrap().unwrap();
+        assert_eq!(cursor.alternatives(), [1]);
+
+        cursor.enter(1).unwrap();
+        assert_eq!(
+            (cursor.variation(), cursor.depth(), cursor.ply()),
+            (1, 1, 0)
+        );
+        assert_eq!(*cursor.board(), Chess::default());
+
+        cursor.next_step().unwrap().unwrap();
+        cursor.next_step().unwrap().unwrap();
+        cursor.enter(2).unwrap();
+
+        let step = cursor.next_step().unwrap().unwrap();
+        assert_eq!(step.shakmaty_move.unwrap().to(), Square::F6);
+        assert_eq!(cursor.next_step().unwrap().unwrap().ply, 3);
+        assert!(cursor.next_step().is_none());
+
+        assert!(cursor.exit());
+        assert!(cursor.exit());
+        assert!(!cursor.exit());
+        assert_eq!(cursor.ply(), 1);
+
+        let step = cursor.next_step().unwrap().unwrap();
+        assert_eq!(step.shakmaty_move.unwrap().to(), Square::E5);
+
+        // Without the position tables, the variation is replayed from the starting position
+        let board: Chess = game
+            .variation_board(2, &cmbr_file.encountered_positions)
+            .unwrap();
+        let replayed: Chess = game.variation_board(2, &HashMap::new()).unwrap();
+        assert_eq!(
+            CmbrPackedPosition::from_position(&board),
+            CmbrPackedPosition::from_position(&replayed)
+        );
+        assert_eq!(
+            CmbrPackedPosition::from_position(&replayed),
+            CmbrPackedPosition::from_fen(
+                "rnbqkbnr/pppppppp/8/8/3P4/8/PPP1PPPP/RNBQKBNR b KQkq - 0 1"
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            game.variation_board::<Chess>(3, &HashMap::new())
+                .unwrap_err(),
+            LibCmbrError::new(LibCmbrErrorType::MissingPosition)
+        );
+
+        // Comments before the first move aren't part of any step
+        let pgn = "{intro} 1. e4 {after e4} e5 *\n\n";
+        let ast: Vec<_> = pgn::iter_pgn(pgn.as_bytes()).collect();
+        let cmbr_file = CmbrFile::from_ast(ast, &mut convertor, false).unwrap();
+        let mut replay = cmbr_file.games[&0].replay::<Chess>().unwrap();
+
+        let leading = replay.leading().unwrap();
+        assert_eq!(
+            leading.comments,
+            [CmbrComment::new(0, CmbrCommentKind::Brace, "intro")]
+        );
+        assert!(leading.nags.is_empty() && leading.variations.is_empty());
+        assert_eq!(
+            replay.next().unwrap().unwrap().comments,
+            [&CmbrComment::new(1, CmbrCommentKind::Brace, "after e4")]
+        );
+        assert!(replay.next().unwrap().unwrap().comments.is_empty());
+
+        // Neither are NAGs and variation pointers
+        let e4: San = "e4".parse().unwrap();
+        let e4 = e4.to_move(&Chess::default()).unwrap();
+        let mut variation = CmbrVariation::new(0);
+        variation.moves = vec![
+            CmbrMove::Nag(5).into(),
+            CmbrMove::VariationPointer(1).into(),
+            SanToCmbrMvConvertor::move_to_cmbr(&e4, Color::White, None),
+        ];
+
+        let mut replay = variation.iter_positions(Chess::default());
+        let leading = replay.leading().unwrap();
+        assert_eq!((leading.nags, leading.variations), (vec![5], vec![1]));
+
+        let step = replay.next().unwrap().unwrap();
+        assert_eq!((step.ply, step.shakmaty_move), (1, Some(e4)));
+        assert!(step.nags.is_empty() && step.variations.is_empty());
+
+        // Their variations start from the same position as the variation
+        let mut game = CmbrGame::new();
+        game.variations.insert(0, variation);
+        game.variations.insert(1, CmbrVariation::new(0));
+        let board: Chess = game.variation_board(1, &HashMap::new()).unwrap();
+        assert_eq!(board, Chess::default());
+    }
+
+    #[test]
+    fn test_game_builder() {
+        let pgn = "[Event \"E\"]\n\n1. e4 {best} $1 (1. d4 d5 (1... Nf6 2. c4)) 1... e5 2. Nf3 -- 1-0\n\n";
+        let ast: Vec<_> = pgn::iter_pgn(pgn.as_bytes()).collect();
+        let mut convertor = SanToCmbrMvConvertor::new(0);
+        let converted = CmbrFile::from_ast(ast, &mut convertor, false).unwrap();
+
+        let headers = vec![("Event".to_owned(), "E".to_owned())];
+        let mut builder = CmbrGameBuilder::<Chess>::new(headers, true).unwrap();
+        let push_san = |builder: &mut CmbrGameBuilder<Chess>, san: &str| {
+            let san: San = san.parse().unwrap();
+            let shakmaty_move = san.to_move(builder.board()).unwrap();
+            builder.push_move(&shakmaty_move).unwrap();
+        };
+
+        assert_eq!(
+            builder.start_variation(),
+            Err(LibCmbrError::new(LibCmbrErrorType::MissingPosition))
+        );
+        push_san(&mut builder, "e4");
+        builder.push_comment(CmbrCommentKind::Brace, "best");
+        builder.push_nag(1).unwrap();
+
+        assert_eq!(builder.start_variation(), Ok(1));
+        assert_eq!(
+            builder.end_variation(),
+            Err(LibCmbrError::new(LibCmbrErrorType::EmptyVariation))
+        );
+        push_san(&mut builder, "d4");
+        push_san(&mut builder, "d5");
+        assert_eq!(builder.start_variation(), Ok(2));
+        push_san(&mut builder, "Nf6");
+        push_san(&mut builder, "c4");
+        assert_eq!(builder.end_variation(), Ok(true));
+        assert_eq!(builder.end_variation(), Ok(true));
+        assert_eq!(builder.end_variation(), Ok(false));
+        assert_eq!(builder.current_variation(), 0);
+
+        push_san(&mut builder, "e5");
+        push_san(&mut builder, "Nf3");
+        builder.push_null_move().unwrap();
+        builder.set_result("1-0").unwrap();
+
+        let mut file = CmbrFile::new(false);
+        assert_eq!(builder.clone().append_to(&mut file), Ok(0));
+        assert_eq!(file.games[&0], converted.games[&0]);
+        assert_eq!(file.encountered_positions, converted.encountered_positions);
+
+        let mut writer = CmbrWriter::new(Vec::new(), false, DEFAULT_COMPRESSION_LEVEL, 0).unwrap();
+        assert_eq!(builder.write_to(&mut writer).unwrap(), 0);
+        let written = CmbrFile::deserialize(&writer.finish().unwrap()).unwrap();
+        assert_eq!(written.games[&0], converted.games[&0]);
+
+        // Moves are checked against the current position
+        let mut builder = CmbrGameBuilder::<Chess>::new(Vec::new(), false).unwrap();
+        let e5: San = "e5".parse().unwrap();
+        let e5 = e5.to_move(&Chess::default().swap_turn().unwrap()).unwrap();
+        assert_eq!(
+            builder.push_move(&e5),
+            Err(LibCmbrError::new(LibCmbrErrorType::IllegalCmbrMv))
+        );
+        assert_eq!(
+            builder.set_result("2-0"),
+            Err(LibCmbrError::new(LibCmbrErrorType::UnknownResult))
+        );
+        // A NAG needs a move to be attached to
+        assert_eq!(
+            builder.push_nag(1),
+            Err(LibCmbrError::new(LibCmbrErrorType::InvalidNag))
+        );
+
+        let (game, positions) = builder.finish().unwrap();
+        assert!(game.encountered_positions.is_empty() && positions.is_empty());
+
+        let headers = vec![("FEN".to_owned(), "not a fen".to_owned())];
+        assert_eq!(
+            CmbrGameBuilder::<Chess>::new(headers, true).unwrap_err(),
+            LibCmbrError::new(LibCmbrErrorType::InvalidFen)
+        );
+
+        // The position type has to match the variant
+        let headers = vec![("Variant".to_owned(), "Atomic".to_owned())];
+        assert_eq!(
+            CmbrGameBuilder::<Chess>::new(headers.clone(), true).unwrap_err(),
+            LibCmbrError::new(LibCmbrErrorType::VariantMismatch)
+        );
+        assert!(CmbrGameBuilder::<Atomic>::new(headers, true).is_ok());
+    }
+
+    #[test]
+    fn test_san_cache() {
+        // The same SAN is a different move in a different position
+        let mut convertor = SanToCmbrMvConvertor::new(/* 16MB */ 16 * 1024 * 1024);
+        let mut boards = [
+            Chess::default(),
+            "4k3/8/8/8/8/8/3N4/4K3 w - - 0 1"
+                .parse::<Fen>()
+                .unwrap()
+                .into_position(CastlingMode::Standard)
+                .unwrap(),
+        ];
+
+        for (board, from) in boards.iter_mut().zip([Square::G1, Square::D2]) {
+            let cmbr = convertor.san_to_cmbr(board, b"Nf3").unwrap();
+
+            assert!(matches!(
+                CmbrMove::try_from(cmbr),
+                Ok(CmbrMove::Normal { from: f, to: Square::F3, .. }) if f == from
+            ));
+        }
+
+        // The cache doesn't change the converted games, and stays within its memory limit
+        let file_path = get_project_root().unwrap().join("data/multiple_games.pgn");
+        let file = File::open(file_path).unwrap();
+        let mut mmap = unsafe { Mmap::map(&file).unwrap() };
+        let ast = pgn::parse_pgn(&mut mmap);
+
+        // Too small to hold a single bucket, so nothing is cached
+        let mut convertor = SanToCmbrMvConvertor::new(1);
+        let expected = CmbrFile::from_ast(ast.clone(), &mut convertor, false).unwrap();
+        assert_eq!(convertor.cached_move_count(), 0);
+
+        for memory_limit in [4 * 1024, 16 * 1024 * 1024] {
+            let mut convertor = SanToCmbrMvConvertor::new(memory_limit);
+
+            for _ in 0..2 {
+                let cmbr_file = CmbrFile::from_ast(ast.clone(), &mut convertor, false).unwrap();
+                assert_eq!(cmbr_file, expected);
+            }
+
+            // Both tables have at most `buckets` buckets, and each of them is evicted once it
+            // holds 7/8 of that many moves
+            let buckets = 1 << (memory_limit as usize * 2 / (5 * SAN_TABLE_BUCKET_SIZE)).ilog2();
+            assert!(buckets * 5 / 2 * SAN_TABLE_BUCKET_SIZE <= memory_limit as usize);
+
+            assert!(convertor.cached_move_count() > 0);
+            assert!(convertor.cached_move_count() <= 2 * (buckets / 8 * 7));
+        }
+
+        // A limit of 0 means the default one
+        let mut convertor = SanToCmbrMvConvertor::new(0);
+        CmbrFile::from_ast(ast, &mut convertor, false).unwrap();
+        assert!(convertor.cached_move_count() > 0);
+    }
+
     // FIXME: bench_san_cmbr is broken
     #[cfg(feature = "benchmark")]
     #[bench]
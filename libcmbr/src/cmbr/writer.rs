@@ -0,0 +1,198 @@
+use super::reader::{CmbrFileFlags, CMBR_FILE_FLAGS, CMBR_HEADER_SIZE, CMBR_MAGIC_BYTES};
+use super::sections::*;
+use super::{insert_position, CmbrFile, CmbrGame, CmbrPackedPosition, PositionKey};
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// Used by `CmbrWriter` when it's given a memory limit of 0
+pub const DEFAULT_WRITER_MEMORY_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Writes a CMBR file incrementally, so the whole file never has to be in memory. Games are
+/// buffered until their estimated size reaches the memory limit, and are then written as
+/// another batch of sections. The section table is written by `finish`, at the end of the file
+/// (See `CmbrFileFlags::Streamed`)
+pub struct CmbrWriter<W: Write> {
+    writer: W,
+    codec: u8,
+    compression_level: i32,
+    memory_limit: u64,
+    /// The games that haven't been written yet, and the positions they reached
+    batch: CmbrFile,
+    batch_size: u64,
+    game_count: u32,
+    offset: u64,
+    entries: Vec<CmbrSectionEntry>,
+}
+
+/// A rough estimate of how many bytes `game` takes up in memory
+pub(crate) fn estimated_game_size(game: &CmbrGame) -> u64 {
+    let headers: usize = game
+        .headers
+        .iter()
+        .map(|(key, value)| 48 + key.len() + value.len())
+        .chain(
+            game.escape_comments
+                .iter()
+                .map(|comment| 24 + comment.len()),
+        )
+        .sum();
+
+    let variations: usize = game
+        .variations
+        .values()
+        .map(|variation| {
+            let comments: usize = variation
+                .comments
+                .iter()
+                .map(|comment| 32 + comment.text.len())
+                .sum();
+
+            64 + variation.moves.len() * std::mem::size_of::<super::CmbrMv>() + comments
+        })
+        .sum();
+
+    return (std::mem::size_of::<CmbrGame>()
+        + headers
+        + variations
+        + game.encountered_positions.len() * 16) as u64;
+}
+
+/// A rough estimate of how many bytes the games of `file` and their positions take up in memory
+pub(crate) fn estimated_file_size(file: &CmbrFile) -> u64 {
+    let positions: u64 = file
+        .encountered_positions
+        .values()
+        .map(|position| 32 + position.0.len() as u64)
+        .sum();
+
+    return file.games.values().map(estimated_game_size).sum::<u64>() + positions;
+}
+
+impl<W: Write> CmbrWriter<W> {
+    /// Writes the header of the file. `compression_level` is ignored if `is_compressed` isn't
+    /// set, and a `memory_limit` of 0 means `DEFAULT_WRITER_MEMORY_LIMIT`
+    pub fn new(
+        mut writer: W,
+        is_compressed: bool,
+        compression_level: i32,
+        memory_limit: u64,
+    ) -> io::Result<Self> {
+        writer.write_all(CMBR_MAGIC_BYTES)?;
+        // The flags, and the number of sections in the header, which is unused
+        writer.write_all(&[CMBR_FILE_FLAGS | CmbrFileFlags::Streamed, 0])?;
+
+        return Ok(Self {
+            writer,
+            codec: CmbrSectionCodec::for_compression(is_compressed),
+            compression_level,
+            memory_limit: if memory_limit == 0 {
+                DEFAULT_WRITER_MEMORY_LIMIT
+            } else {
+                memory_limit
+            },
+            batch: CmbrFile::new(is_compressed),
+            batch_size: 0,
+            game_count: 0,
+            offset: CMBR_HEADER_SIZE as u64,
+            entries: Vec::new(),
+        });
+    }
+
+    /// The amount of games pushed so far. Games get their Ids in the order they're pushed
+    pub fn game_count(&self) -> u32 {
+        return self.game_count;
+    }
+
+    /// How many more bytes of games can be buffered before they're written
+    pub fn remaining_memory(&self) -> u64 {
+        return self.memory_limit.saturating_sub(self.batch_size);
+    }
+
+    /// Adds a game to the file. `positions` has to contain the FEN of every position that the
+    /// game reached (See `CmbrGame::encountered_positions`). Other positions are ignored. The
+    /// keys of the positions can change, like in `move_positions`
+    pub fn push_game(
+        &mut self,
+        mut game: CmbrGame,
+        positions: &HashMap<PositionKey, CmbrPackedPosition>,
+    ) -> io::Result<()> {
+        self.batch_size += estimated_game_size(&game);
+
+        for key in game.encountered_positions.values_mut() {
+            let Some(position) = positions.get(key) else {
+                continue;
+            };
+
+            let position_count = self.batch.encountered_positions.len();
+            *key = insert_position(&mut self.batch.encountered_positions, *key, position);
+
+            if self.batch.encountered_positions.len() != position_count {
+                self.batch_size += 32 + position.0.len() as u64;
+            }
+        }
+
+        self.batch.games.insert(self.game_count, game);
+        self.game_count += 1;
+
+        if self.batch_size >= self.memory_limit {
+            self.flush_batch()?;
+        }
+
+        return Ok(());
+    }
+
+    /// Adds every game of `file` to the file, in the order of their Ids
+    pub fn push_file(&mut self, mut file: CmbrFile) -> io::Result<()> {
+        let mut ids: Vec<u32> = file.games.keys().copied().collect();
+        ids.sort_unstable();
+
+        for id in ids {
+            // SAFE: Safe. `id` is a key of `file.games`
+            let game = unsafe { file.games.remove(&id).unwrap_unchecked() };
+            self.push_game(game, &file.encountered_positions)?;
+        }
+
+        return Ok(());
+    }
+
+    /// Writes the buffered games as a batch of sections
+    pub fn flush_batch(&mut self) -> io::Result<()> {
+        if self.batch.games.is_empty() {
+            return Ok(());
+        }
+
+        let is_compressed = self.batch.is_compressed;
+        let batch = std::mem::replace(&mut self.batch, CmbrFile::new(is_compressed));
+        self.batch_size = 0;
+
+        for (kind, section) in batch.encode_sections(self.codec, self.compression_level) {
+            self.writer.write_all(&section)?;
+
+            self.entries.push(CmbrSectionEntry {
+                kind,
+                codec: self.codec,
+                offset: self.offset,
+                length: section.len() as u64,
+            });
+            self.offset += section.len() as u64;
+        }
+
+        return Ok(());
+    }
+
+    /// Writes the remaining games and the section table, and returns the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_batch()?;
+
+        for entry in &self.entries {
+            self.writer.write_all(&entry.to_bytes())?;
+        }
+
+        self.writer
+            .write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        self.writer.flush()?;
+
+        return Ok(self.writer);
+    }
+}
@@ -10,7 +10,23 @@ pub enum LibCmbrErrorType {
     #[default]
     Ok = 0,
     ShouldBeUnreachable,
+    #[deprecated(note = "Crazyhouse games are supported, so this is never returned")]
     CrazyHouseNotSupported,
+    IllegalCmbrMv,
+    InvalidCmbrMv,
+    InvalidMagicBytes,
+    TruncatedFile,
+    CorruptedFile,
+    InvalidFen,
+    InvalidSan,
+    UnknownResult,
+    InvalidNag,
+    EmptyVariation,
+    MissingPosition,
+    TooManyVariations,
+    VariantMismatch,
+    InvalidMoveNumber,
+    NotAnAlternative,
 }
 
 // A struct with libcmbr reports errors
@@ -25,7 +41,23 @@ impl fmt::Display for LibCmbrError {
 
         error_string.push_str(match self.kind {
             LibCmbrErrorType::ShouldBeUnreachable => "This should be unreachable",
+            #[allow(deprecated)]
             LibCmbrErrorType::CrazyHouseNotSupported => "Crazyhouse is not supported yet",
+            LibCmbrErrorType::IllegalCmbrMv => "Encountered a CMBR-MV that isn't legal in the current position",
+            LibCmbrErrorType::InvalidCmbrMv => "The bit pattern isn't a valid CMBR-MV",
+            LibCmbrErrorType::InvalidMagicBytes => "Not a CMBR file (Expected the file to start with `CMBR!`)",
+            LibCmbrErrorType::TruncatedFile => "The CMBR file is truncated",
+            LibCmbrErrorType::CorruptedFile => "The CMBR file is corrupted",
+            LibCmbrErrorType::InvalidFen => "Encountered an invalid FEN",
+            LibCmbrErrorType::InvalidSan => "Encountered a SAN that isn't legal in the current position",
+            LibCmbrErrorType::UnknownResult => "Encountered an unknown game result",
+            LibCmbrErrorType::InvalidNag => "Encountered an invalid NAG or an unknown move annotation",
+            LibCmbrErrorType::EmptyVariation => "Encountered an empty variation",
+            LibCmbrErrorType::MissingPosition => "The position a variation starts from wasn't found",
+            LibCmbrErrorType::TooManyVariations => "A game has more variations than CMBR-MVs can point to (65535)",
+            LibCmbrErrorType::VariantMismatch => "The position type doesn't match the variant of the game",
+            LibCmbrErrorType::InvalidMoveNumber => "Encountered a move number that is out of range or doesn't match the position",
+            LibCmbrErrorType::NotAnAlternative => "The variation isn't an alternative to the last replayed move",
             LibCmbrErrorType::Ok => "Ok. (This should be generally unreachable, and if you're seeing this, something probably went very wrong)"
         });
 
@@ -43,4 +75,67 @@ impl LibCmbrError {
     pub fn ok() -> Self {
         return Self::default();
     }
+
+    pub fn kind(&self) -> LibCmbrErrorType {
+        return self.kind;
+    }
+}
+
+/// An error encountered while converting a game, and where it was encountered
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LibCmbrDiagnostic {
+    pub kind: LibCmbrErrorType,
+    /// Index of the game in the converted PGN
+    pub game: usize,
+    /// Half move the error was encountered at, counted from the standard starting position
+    pub half_move: u16,
+    /// FEN of the position the error was encountered in, if there is one
+    pub fen: Option<String>,
+    /// The offending SAN, NAG, annotation or result, if there is one
+    pub token: Option<String>,
+}
+
+impl fmt::Display for LibCmbrDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} on game N{} at half move {}",
+            LibCmbrError::new(self.kind),
+            self.game,
+            self.half_move
+        )?;
+
+        if let Some(token) = &self.token {
+            write!(f, " | Token: {token}")?;
+        }
+
+        if let Some(fen) = &self.fen {
+            write!(f, " | Fen: {fen}")?;
+        }
+
+        return Ok(());
+    }
+}
+
+impl error::Error for LibCmbrDiagnostic {}
+
+impl LibCmbrDiagnostic {
+    pub fn new(kind: LibCmbrErrorType, game: usize, half_move: u16) -> Self {
+        return Self {
+            kind,
+            game,
+            half_move,
+            ..Default::default()
+        };
+    }
+
+    pub fn with_fen(mut self, fen: impl Into<String>) -> Self {
+        self.fen = Some(fen.into());
+        return self;
+    }
+
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        return self;
+    }
 }
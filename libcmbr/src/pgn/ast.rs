@@ -2,8 +2,7 @@ use litemap::LiteMap;
 use pgn_lexer::parser::Token;
 use std::collections::VecDeque;
 
-use crate::utils::nth_prime_number;
-
+/// Variations are numbered from 1 in the order they're opened in. The main variation is 0
 pub type VariationPointerT = u32;
 
 /// An enumeration representing different types of PGN tokens.
@@ -13,6 +12,9 @@ pub enum PgnToken<'a> {
     Token(Token<'a>),
     /// Represents a pointer to a variation.
     VariationPointer(VariationPointerT),
+    /// Represents a `;` comment, which lasts until the end of the line. The lexer returns these
+    /// as `Token::Commentary`, so they're only told apart by `PgnTokenIterator`
+    LineComment(&'a [u8]),
     /// Represents no token. This is the default variant.
     #[default]
     None,
@@ -33,136 +35,99 @@ pub struct PgnGame<'a> {
     pub variations: LiteMap<VariationPointerT, PgnVariation<'a>>,
 }
 
-/// Builds an ast (represented as `a Vec<PgnGame>`) from the inputted Token list
+/// Builds an ast (represented as `a Vec<PgnGame>`) from the inputted Token list. `;` comments
+/// are left as `Token::Commentary` (See `iter_pgn`)
 pub fn build_pgn_ast<'a>(tokens: &mut VecDeque<Token<'a>>) -> Vec<PgnGame<'a>> {
-    let mut tree: Vec<PgnGame<'a>> = Vec::new();
-    let mut game_number = 0;
-    let mut amount_of_encountered_variations = 1;
-
-    tree.push(PgnGame::default());
-    // SAFE: Safe
-    unsafe {
-        let value = tree.get_unchecked_mut(0);
-
-        value.global_tokens = Vec::new();
-        value.variations.insert(0, PgnVariation::default());
-    }
-
-    while !tokens.is_empty() {
-        next_token(
-            tokens,
-            &mut tree,
-            &mut game_number,
-            0,
-            &mut amount_of_encountered_variations,
-        );
-    }
-
-    tree.pop();
+    return PgnGameIterator::new(tokens.drain(..).map(PgnToken::Token)).collect();
+}
 
-    tree
+/// Builds `PgnGame`s one at a time from an iterator over the tokens of the lexer (See
+/// `PgnTokenIterator`). Only the game that is being built is kept in memory, and nested
+/// variations don't grow the stack. Tokens after the last result are dropped
+pub struct PgnGameIterator<'a, I: Iterator<Item = PgnToken<'a>>> {
+    tokens: I,
 }
 
-macro_rules! push_token {
-    ($tree:expr, $game_number:expr, $variation_depth:expr, $token:expr) => {
-        $tree
-            .get_mut($game_number as usize)
-            .unwrap()
-            .variations
-            .get_mut($variation_depth)
-            .unwrap()
-            .0
-            .push($token)
-    };
+impl<'a, I: Iterator<Item = PgnToken<'a>>> PgnGameIterator<'a, I> {
+    pub fn new(tokens: I) -> Self {
+        return Self { tokens };
+    }
 }
 
-fn next_token<'a>(
-    tokens: &mut VecDeque<Token<'a>>,
-    tree: &mut Vec<PgnGame<'a>>,
-    game_number: &mut u32,
-    variation_depth: VariationPointerT,
-    amount_of_encountered_variations: &mut u16,
-) {
-    // NOTE: I don't know if this is slow. (Like this whole approach) I'm just gonna pretend it isn't until it causes problems
-    // NOTE: This should be safe when called correctly. this function is only used locally, so it
-    // SAFE: Should be safe
-    let token = unsafe { tokens.pop_front().unwrap_unchecked() };
-
-    match token {
-        Token::Move(_)
-        | Token::Commentary(_)
-        | Token::NAG(_)
-        | Token::MoveAnnotation(_)
-        | Token::MoveNumber(_, _) => {
-            push_token!(tree, *game_number, &variation_depth, PgnToken::Token(token))
-        }
-        Token::TagSymbol(_) | Token::TagString(_) => tree
-            .get_mut(*game_number as usize)
-            .unwrap()
-            .global_tokens
-            .push(token),
-        Token::NullMove(_) => {}
-        Token::EscapeComment(_) => { /* NOTE: IDK what to do with this */ }
-        Token::Result(_) => {
-            tree.get_mut(*game_number as usize)
-                .unwrap()
-                .global_tokens
-                .push(token);
-
-            *game_number += 1;
-            *amount_of_encountered_variations = 1;
-
-            tree.push(PgnGame::default());
-            // SAFE: safe
-            unsafe {
-                let value = &mut tree.get_unchecked_mut(*game_number as usize);
-
-                value.global_tokens = Vec::new();
-                value
-                    .variations
-                    .insert(variation_depth, PgnVariation::default());
-            }
-        }
-        Token::StartVariation(_) => {
-            let new_variation_depth = nth_prime_number::<u32>(*amount_of_encountered_variations as u32) * (variation_depth + 1);
-
-            *amount_of_encountered_variations += 1;
-
-            push_token!(
-                tree,
-                *game_number,
-                &variation_depth,
-                PgnToken::VariationPointer(new_variation_depth)
-            );
-
-            // SAFE: Safe
-            unsafe {
-                let value = &mut tree.get_unchecked_mut(*game_number as usize);
-                value
+impl<'a, I: Iterator<Item = PgnToken<'a>>> Iterator for PgnGameIterator<'a, I> {
+    type Item = PgnGame<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut game = PgnGame::default();
+        game.variations.insert(0, PgnVariation::default());
+
+        // The variations that are currently open. Tokens are pushed to the last one
+        let mut open_variations: Vec<VariationPointerT> = vec![0];
+        let mut amount_of_encountered_variations: VariationPointerT = 0;
+
+        for token in self.tokens.by_ref() {
+            // SAFE: Safe. The main variation is never closed
+            let variation_pointer = unsafe { *open_variations.last().unwrap_unchecked() };
+
+            let token = match token {
+                PgnToken::Token(token) => token,
+                PgnToken::LineComment(_) => {
+                    game.variations.get_mut(&variation_pointer).unwrap().0.push(token);
+                    continue;
+                }
+                // Variation pointers are only made here
+                PgnToken::VariationPointer(_) | PgnToken::None => continue,
+            };
+
+            match token {
+                // Escape comments before the tags belong to the game. Ones between the tags and
+                // the movetext are kept in the movetext, so they stay after the tags
+                Token::EscapeComment(_)
+                    if open_variations.len() == 1
+                        && game.variations[&0].0.is_empty()
+                        && !game.global_tokens.iter().any(|t| matches!(t, Token::TagSymbol(_))) =>
+                {
+                    game.global_tokens.push(token)
+                }
+                Token::Move(_)
+                | Token::NullMove(_)
+                | Token::EscapeComment(_)
+                | Token::Commentary(_)
+                | Token::NAG(_)
+                | Token::MoveAnnotation(_)
+                | Token::MoveNumber(_, _) => game
                     .variations
-                    .insert(new_variation_depth, PgnVariation::default());
+                    .get_mut(&variation_pointer)
+                    .unwrap()
+                    .0
+                    .push(PgnToken::Token(token)),
+                Token::TagSymbol(_) | Token::TagString(_) => game.global_tokens.push(token),
+                Token::Result(_) => {
+                    game.global_tokens.push(token);
+                    return Some(game);
+                }
+                Token::StartVariation(_) => {
+                    amount_of_encountered_variations += 1;
+                    let new_variation_pointer = amount_of_encountered_variations;
+
+                    game.variations
+                        .get_mut(&variation_pointer)
+                        .unwrap()
+                        .0
+                        .push(PgnToken::VariationPointer(new_variation_pointer));
+                    game.variations
+                        .insert(new_variation_pointer, PgnVariation::default());
+
+                    open_variations.push(new_variation_pointer);
+                }
+                Token::EndVariation(_) => {
+                    if open_variations.len() > 1 {
+                        open_variations.pop();
+                    }
+                }
             }
-
-            next_token(
-                tokens,
-                tree,
-                game_number,
-                new_variation_depth,
-                amount_of_encountered_variations,
-            );
         }
-        Token::EndVariation(_) => {
-            return;
-        }
-    }
 
-    if !tokens.is_empty() {
-        next_token(
-            tokens,
-            tree,
-            game_number,
-            variation_depth,
-            amount_of_encountered_variations,
-        );
+        return None;
     }
 }
@@ -8,19 +8,62 @@ use memmap2::Mmap;
 use pgn_lexer::parser;
 pub use pgn_lexer::parser::Token;
 
-/// Lexes a PGN file (Generates a `Vec<Token>`) from the given Mmap
-pub fn lex_pgn(input_mmap: &mut Mmap) -> VecDeque<Token> {
-    let mut bytes = &input_mmap[..];
-    if bytes[0..3] == [239u8, 187u8, 191u8] {
-        bytes = &bytes[3..];
+/// Skips the UTF-8 BOM of a PGN file if it has one
+fn strip_bom(bytes: &[u8]) -> &[u8] {
+    return bytes.strip_prefix(&[239u8, 187u8, 191u8]).unwrap_or(bytes);
+}
+
+/// The tokens of a PGN file. The lexer returns `;` comments as `Token::Commentary`, like brace
+/// comments, so they're told apart here and returned as `PgnToken::LineComment`
+pub struct PgnTokenIterator<'a> {
+    tokens: parser::PGNTokenIterator<'a>,
+    /// The bytes `tokens` are lexed from
+    bytes: &'a [u8],
+}
+
+impl<'a> PgnTokenIterator<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        let bytes = strip_bom(bytes);
+
+        return Self {
+            tokens: parser::PGNTokenIterator::new(bytes),
+            bytes,
+        };
+    }
+}
+
+impl<'a> Iterator for PgnTokenIterator<'a> {
+    type Item = PgnToken<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = self.tokens.next()?;
+
+        if let Token::Commentary(comment) = token {
+            // The comment is a part of `bytes`, right after its `{` or `;`
+            let start = comment.as_ptr() as usize - self.bytes.as_ptr() as usize;
+
+            if start > 0 && self.bytes[start - 1] == b';' {
+                return Some(PgnToken::LineComment(comment));
+            }
+        }
+
+        return Some(PgnToken::Token(token));
     }
+}
 
-    let tokens = parser::PGNTokenIterator::new(bytes);
+/// Lexes a PGN file (Generates a `Vec<Token>`) from the given Mmap. `;` comments are left as
+/// `Token::Commentary` (See `PgnTokenIterator`)
+pub fn lex_pgn(input_mmap: &mut Mmap) -> VecDeque<Token> {
+    return parser::PGNTokenIterator::new(strip_bom(&input_mmap[..])).collect();
+}
 
-    return tokens.collect();
+/// Lexes and parses a PGN file lazily, one game at a time. Unlike `parse_pgn`, only the game
+/// that is being parsed is kept in memory
+pub fn iter_pgn(bytes: &[u8]) -> PgnGameIterator<'_, PgnTokenIterator<'_>> {
+    return PgnGameIterator::new(PgnTokenIterator::new(bytes));
 }
 
 /// First lexes mmap, then generates AST and returns
 pub fn parse_pgn(input_mmap: &mut Mmap) -> Vec<PgnGame> {
-    return build_pgn_ast(&mut lex_pgn(input_mmap));
+    return iter_pgn(&input_mmap[..]).collect();
 }
@@ -3,7 +3,7 @@ extern crate test;
 #[cfg(test)]
 mod pgn_tests {
     #[allow(unused_imports)]
-    use crate::pgn::{self, lex_pgn, Token, VecDeque};
+    use crate::pgn::{self, lex_pgn, PgnToken, Token, VecDeque};
     use memmap2::Mmap;
     use project_root::get_project_root;
     use std::fs::File;
@@ -38,11 +38,96 @@ mod pgn_tests {
 
         let ast = crate::pgn::parse_pgn(&mut mmap);
 
-        let ast_expected = "[PgnGame { global_tokens: [TagSymbol([69]), TagString([69]), TagSymbol([83]), TagString([83]), Result([42])], variations: LiteMap { values: [(0, PgnVariation([Token(MoveNumber(1, false)), Token(Move([101, 52])), VariationPointer(2), VariationPointer(3), Token(MoveNumber(1, true)), Token(Move([101, 53])), Token(MoveNumber(2, false)), Token(Move([78, 102, 51])), VariationPointer(5), VariationPointer(11), Token(MoveNumber(2, true)), Token(Move([78, 99, 54])), Token(MoveNumber(3, false)), Token(Move([66, 99, 52])), Token(Move([66, 99, 53])), Token(MoveNumber(4, false)), Token(Move([79, 45, 79]))])), (2, PgnVariation([Token(MoveNumber(1, false)), Token(Move([100, 52]))])), (3, PgnVariation([Token(MoveNumber(1, false)), Token(Move([99, 52]))])), (5, PgnVariation([Token(MoveNumber(2, false)), Token(Move([78, 99, 51])), Token(Move([100, 53])), VariationPointer(42)])), (11, PgnVariation([Token(MoveNumber(2, false)), Token(Move([100, 51]))])), (42, PgnVariation([Token(MoveNumber(2, true)), Token(Move([100, 54]))]))], _key_type: PhantomData<u32>, _value_type: PhantomData<libcmbr::pgn::ast::PgnVariation> } }, PgnGame { global_tokens: [TagSymbol([69]), TagString([69]), TagSymbol([83]), TagString([83]), Result([42])], variations: LiteMap { values: [(0, PgnVariation([Token(MoveNumber(1, false)), Token(Move([101, 52])), VariationPointer(2), VariationPointer(3), Token(MoveNumber(1, true)), Token(Move([101, 53])), Token(MoveNumber(2, false)), Token(Move([78, 102, 51])), VariationPointer(5), VariationPointer(11), Token(Commentary([32, 67, 111, 109, 109, 101, 110, 116, 32]))])), (2, PgnVariation([Token(MoveNumber(1, false)), Token(Move([100, 52]))])), (3, PgnVariation([Token(MoveNumber(1, false)), Token(Move([99, 52]))])), (5, PgnVariation([Token(MoveNumber(2, false)), Token(Move([78, 99, 51])), Token(Move([100, 53])), VariationPointer(42)])), (11, PgnVariation([Token(MoveNumber(2, false)), Token(Move([100, 51]))])), (42, PgnVariation([Token(MoveNumber(2, true)), Token(Move([100, 54]))]))], _key_type: PhantomData<u32>, _value_type: PhantomData<libcmbr::pgn::ast::PgnVariation> } }]";
+        let ast_expected = "[PgnGame { global_tokens: [TagSymbol([69]), TagString([69]), TagSymbol([83]), TagString([83]), Result([42])], variations: LiteMap { values: [(0, PgnVariation([Token(MoveNumber(1, false)), Token(Move([101, 52])), VariationPointer(1), VariationPointer(2), Token(MoveNumber(1, true)), Token(Move([101, 53])), Token(MoveNumber(2, false)), Token(Move([78, 102, 51])), VariationPointer(3), VariationPointer(5), Token(MoveNumber(2, true)), Token(Move([78, 99, 54])), Token(MoveNumber(3, false)), Token(Move([66, 99, 52])), Token(Move([66, 99, 53])), Token(MoveNumber(4, false)), Token(Move([79, 45, 79]))])), (1, PgnVariation([Token(MoveNumber(1, false)), Token(Move([100, 52]))])), (2, PgnVariation([Token(MoveNumber(1, false)), Token(Move([99, 52]))])), (3, PgnVariation([Token(MoveNumber(2, false)), Token(Move([78, 99, 51])), Token(Move([100, 53])), VariationPointer(4)])), (4, PgnVariation([Token(MoveNumber(2, true)), Token(Move([100, 54]))])), (5, PgnVariation([Token(MoveNumber(2, false)), Token(Move([100, 51]))]))], _key_type: PhantomData<u32>, _value_type: PhantomData<libcmbr::pgn::ast::PgnVariation> } }, PgnGame { global_tokens: [TagSymbol([69]), TagString([69]), TagSymbol([83]), TagString([83]), Result([42])], variations: LiteMap { values: [(0, PgnVariation([Token(MoveNumber(1, false)), Token(Move([101, 52])), VariationPointer(1), VariationPointer(2), Token(MoveNumber(1, true)), Token(Move([101, 53])), Token(MoveNumber(2, false)), Token(Move([78, 102, 51])), VariationPointer(3), VariationPointer(5), Token(Commentary([32, 67, 111, 109, 109, 101, 110, 116, 32]))])), (1, PgnVariation([Token(MoveNumber(1, false)), Token(Move([100, 52]))])), (2, PgnVariation([Token(MoveNumber(1, false)), Token(Move([99, 52]))])), (3, PgnVariation([Token(MoveNumber(2, false)), Token(Move([78, 99, 51])), Token(Move([100, 53])), VariationPointer(4)])), (4, PgnVariation([Token(MoveNumber(2, true)), Token(Move([100, 54]))])), (5, PgnVariation([Token(MoveNumber(2, false)), Token(Move([100, 51]))]))], _key_type: PhantomData<u32>, _value_type: PhantomData<libcmbr::pgn::ast::PgnVariation> } }]";
 
         assert_eq!(format!("{:?}", ast), ast_expected);
     }
 
+    #[test]
+    fn test_iter_pgn() {
+        let file_path = get_project_root().unwrap().join("data/multiple_games.pgn");
+        let file = File::open(file_path).unwrap();
+        let mmap = unsafe { Mmap::map(&file).unwrap() };
+        let mut lexed_mmap = unsafe { Mmap::map(&file).unwrap() };
+
+        let games: Vec<_> = pgn::iter_pgn(&mmap[..]).collect();
+        assert_eq!(games, pgn::build_pgn_ast(&mut lex_pgn(&mut lexed_mmap)));
+        assert_eq!(games.len(), 6);
+
+        // A UTF-8 BOM is skipped
+        let mut with_bom = vec![239u8, 187u8, 191u8];
+        with_bom.extend_from_slice(&mmap[..]);
+        assert!(pgn::iter_pgn(&with_bom).eq(games));
+
+        // Neither long games nor deeply nested variations grow the stack
+        let mut long_game = String::from("[Event \"Long\"]\n\n");
+        for i in 1..=50_000 {
+            long_game.push_str(&format!("{i}. Nf3 Nf6 {}. Ng1 Ng8 ", i + 1));
+        }
+        long_game.push_str(&"(1. d4 ".repeat(8));
+        long_game.push_str(&")".repeat(8));
+        long_game.push_str(" *");
+
+        let mut games = pgn::iter_pgn(long_game.as_bytes());
+        let game = games.next().unwrap();
+
+        assert!(games.next().is_none());
+        assert_eq!(game.variations.len(), 9);
+        assert_eq!(
+            game.variations[&0]
+                .0
+                .iter()
+                .filter(|token| matches!(token, PgnToken::Token(Token::Move(_))))
+                .count(),
+            200_000
+        );
+
+        // Games without a result at the end of the input are dropped
+        assert_eq!(pgn::iter_pgn(b"1. e4 e5 * 1. d4").count(), 1);
+
+        // `;` comments are only told apart from brace comments by `PgnTokenIterator`
+        let pgn = b"%escape\n[E \"E\"]\n\n1. e4 {brace} ;line\n%moves\n*";
+        let game = pgn::iter_pgn(pgn).next().unwrap();
+        assert_eq!(game.global_tokens[0], Token::EscapeComment(b"escape"));
+        assert_eq!(
+            game.variations[&0].0[2..],
+            [
+                PgnToken::Token(Token::Commentary(b"brace")),
+                PgnToken::LineComment(b"line"),
+                PgnToken::Token(Token::EscapeComment(b"moves")),
+            ]
+        );
+
+        let mut lexed: VecDeque<_> = pgn_lexer::parser::PGNTokenIterator::new(pgn).collect();
+        let game = &pgn::build_pgn_ast(&mut lexed)[0];
+        assert_eq!(
+            game.variations[&0].0[3],
+            PgnToken::Token(Token::Commentary(b"line"))
+        );
+
+        let mut bom_tokens = pgn::PgnTokenIterator::new(b"\xEF\xBB\xBF;line\n{brace}");
+        assert_eq!(bom_tokens.next(), Some(PgnToken::LineComment(b"line")));
+        assert_eq!(
+            bom_tokens.next(),
+            Some(PgnToken::Token(Token::Commentary(b"brace")))
+        );
+
+        // Null moves are kept like moves
+        let game = pgn::iter_pgn(b"1. e4 -- 2. d4 Z0 *").next().unwrap();
+        assert_eq!(
+            game.variations[&0].0[2..5],
+            [
+                PgnToken::Token(Token::NullMove(b"--")),
+                PgnToken::Token(Token::MoveNumber(2, false)),
+                PgnToken::Token(Token::Move(b"d4")),
+            ]
+        );
+        assert_eq!(
+            game.variations[&0].0[5],
+            PgnToken::Token(Token::NullMove(b"Z0"))
+        );
+    }
+
     #[cfg(feature = "benchmark")]
     #[bench]
     fn bench_ast(b: &mut Bencher) {
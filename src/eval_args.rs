@@ -1,15 +1,77 @@
 use super::Cli;
-use libcmbr::cmbr::{CmbrFile, SanToCmbrMvConvertor};
-use libcmbr::pgn::parse_pgn;
+use libcmbr::cmbr::{CmbrConversionOptions, CmbrFile, CmbrWriter, SanToCmbrMvConvertor};
+use libcmbr::pgn::iter_pgn;
 
 use memmap2::Mmap;
+use std::fmt::Display;
 use std::fs::File;
-use std::io::Write;
+use std::io::BufWriter;
+
+/// Removes the output of an aborted conversion, which has no section table so it can't be read,
+/// and exits
+fn abort_conversion(error: impl Display, file_name: &str, output: &str) -> ! {
+    eprintln!("[ERROR] {error}. File name: {file_name}");
+    let _ = std::fs::remove_file(output);
+    std::process::exit(1);
+}
 
 pub fn eval_args(cli: &Cli) {
     match cli.command.as_ref().unwrap() {
-        crate::CommandE::Cmbr2pgn(_args) => {
-            todo!()
+        crate::CommandE::Cmbr2pgn(args) => {
+            let file_name = args.input.clone();
+            let file = File::open(&file_name);
+
+            if file.is_err() {
+                eprintln!("[ERROR] {}. File name: {file_name}", file.err().unwrap());
+                std::process::exit(1);
+            }
+
+            // SAFE: Safe
+            let file = unsafe { file.unwrap_unchecked() };
+            let mmap = unsafe { Mmap::map(&file) };
+
+            if mmap.is_err() {
+                eprintln!("[ERROR] {}. File name: {file_name}", mmap.err().unwrap());
+                std::process::exit(1);
+            }
+
+            // SAFE: Safe
+            let mmap = unsafe { mmap.unwrap_unchecked() };
+
+            let cmbr_file = CmbrFile::from_mmap(&mmap);
+            if cmbr_file.is_err() {
+                eprintln!(
+                    "[ERROR] {}. File name: {file_name}",
+                    cmbr_file.err().unwrap()
+                );
+                std::process::exit(1);
+            }
+
+            // SAFE: Safe
+            let cmbr_file = unsafe { cmbr_file.unwrap_unchecked() };
+
+            let result = if args.output.is_empty() {
+                cmbr_file.to_pgn(&mut BufWriter::new(std::io::stdout().lock()))
+            } else {
+                let output = File::create(&args.output);
+                if output.is_err() {
+                    eprintln!(
+                        "[ERROR] {}. File name: {}",
+                        output.err().unwrap(),
+                        args.output
+                    );
+                    std::process::exit(1);
+                }
+
+                // SAFE: Safe
+                let output = unsafe { output.unwrap_unchecked() };
+                cmbr_file.to_pgn(&mut BufWriter::new(output))
+            };
+
+            if result.is_err() {
+                eprintln!("[ERROR] {}. File name: {file_name}", result.err().unwrap());
+                std::process::exit(1);
+            }
         }
 
         crate::CommandE::Pgn2cmbr(args) => {
@@ -31,17 +93,80 @@ pub fn eval_args(cli: &Cli) {
             }
 
             // SAFE: Safe
-            let mut mmap = unsafe { mmap.unwrap_unchecked() };
+            let mmap = unsafe { mmap.unwrap_unchecked() };
+
+            let output = File::create(&args.output);
+            if output.is_err() {
+                eprintln!(
+                    "[ERROR] {}. File name: {}",
+                    output.err().unwrap(),
+                    args.output
+                );
+                std::process::exit(1);
+            }
+
+            // SAFE: Safe
+            let output = unsafe { output.unwrap_unchecked() };
+
+            // Games are converted and written one batch at a time, so the memory used is
+            // bounded by the table memory limit instead of the size of the input. Half of it
+            // bounds the batch, and the other half is shared by the SAN caches. A limit of 0
+            // means the default one, so neither half is rounded down to it
+            let (writer_memory_limit, convertor_memory_limit) = if args.table_mem_limit == 0 {
+                (0, 0)
+            } else {
+                let writer_memory_limit = (args.table_mem_limit / 2).max(1);
+                (writer_memory_limit, (args.table_mem_limit - writer_memory_limit).max(1))
+            };
 
-            let ast = parse_pgn(&mut mmap);
-            let mut convertor = SanToCmbrMvConvertor::new(args.table_mem_limit);
+            let writer = CmbrWriter::new(
+                BufWriter::new(output),
+                args.enable_compression,
+                args.compression_level as i32,
+                writer_memory_limit,
+            );
 
-            let cmbr_file =
-                CmbrFile::from_ast(ast, &mut convertor, args.enable_compression).unwrap();
+            if writer.is_err() {
+                abort_conversion(writer.err().unwrap(), &args.output, &args.output);
+            }
+
+            // SAFE: Safe
+            let mut writer = unsafe { writer.unwrap_unchecked() };
 
-            let mut f = File::create(&args.output).unwrap();
-            let serialized = cmbr_file.serialize();
-            f.write(&serialized[..]).unwrap();
+            let games = iter_pgn(&mmap[..]);
+            let on_diagnostic = |diagnostic| eprintln!("[WARN] {diagnostic}");
+            let options = CmbrConversionOptions {
+                is_compressed: args.enable_compression,
+                with_positions: args.position_tables,
+                policy: args.invalid_games,
+            };
+
+            let result = if args.threads == 1 {
+                let mut convertor = SanToCmbrMvConvertor::new(convertor_memory_limit);
+                writer.convert_games(
+                    games,
+                    &mut convertor,
+                    options,
+                    on_diagnostic,
+                )
+            } else {
+                writer.convert_games_multithreaded(
+                    games,
+                    convertor_memory_limit,
+                    args.threads,
+                    options,
+                    on_diagnostic,
+                )
+            };
+
+            if result.is_err() {
+                abort_conversion(result.err().unwrap(), &file_name, &args.output);
+            }
+
+            let result = writer.finish();
+            if result.is_err() {
+                abort_conversion(result.err().unwrap(), &args.output, &args.output);
+            }
         }
 
         crate::CommandE::License => {
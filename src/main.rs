@@ -4,6 +4,7 @@ mod eval_args;
 mod utils;
 
 use lexopt::prelude::*;
+use libcmbr::cmbr::CmbrInvalidGamePolicy;
 use std::process::exit;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -27,6 +28,9 @@ pub struct Pgn2CmbrArgs {
     enable_compression: bool,
     compression_level: u8,
     table_mem_limit: u64,
+    threads: usize,
+    invalid_games: CmbrInvalidGamePolicy,
+    position_tables: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -66,8 +70,9 @@ fn print_usage() {
     println!("note: Options inside of square brackets ([]) are optional\n");
     println!("Commands:");
     println!("  cmbr2pgn --input {{INPUT_FILE}} [--output {{OUTPUT_FILE}} --table-memory-limit {{LIMIT}} ]");
-    println!("  pgn2cmbr --input {{INPUT_FILE}} [--output {{OUTPUT_FILE}} --table-memory-limit {{LIMIT}} --enable_compression ]");
+    println!("  pgn2cmbr --input {{INPUT_FILE}} [--output {{OUTPUT_FILE}} --table-memory-limit {{LIMIT}} --enable-compression {{true|false}} --compression-level {{1-22}} --threads {{THREADS}} --invalid-games {{abort|skip|keep}} --position-tables {{true|false}} ]");
     println!("  license");
+    println!("\nnote: pgn2cmbr uses half of the table memory limit for the games it buffers before writing them, and splits the other half between the SAN caches of its threads");
 }
 
 fn parse_args() -> Cli {
@@ -144,6 +149,73 @@ fn parse_args() -> Cli {
                 }
             }
 
+            Short('l') | Long("compression-level") => {
+                let compression_level = parser.value().unwrap().parse();
+
+                if compression_level.is_err() {
+                    eprintln!("Invalid option for compression-level (Expected a number between 1 and 22). Run `cmbrcc --help` for help.");
+                    std::process::exit(1);
+                }
+
+                if let Some(CommandE::Pgn2cmbr(ref mut args)) = command {
+                    args.compression_level = compression_level.unwrap();
+                } else {
+                    eprintln!("Invalid option --compression-level for this subcommand. Run `cmbrcc --help` for help.");
+                    std::process::exit(1);
+                }
+            }
+
+            Short('t') | Long("threads") => {
+                let threads = parser.value().unwrap().parse();
+
+                if threads.is_err() {
+                    eprintln!("Invalid option for threads (Expected a positive number). Run `cmbrcc --help` for help.");
+                    std::process::exit(1);
+                }
+
+                if let Some(CommandE::Pgn2cmbr(ref mut args)) = command {
+                    args.threads = threads.unwrap();
+                } else {
+                    eprintln!("Invalid option --threads for this subcommand. Run `cmbrcc --help` for help.");
+                    std::process::exit(1);
+                }
+            }
+
+            Long("invalid-games") => {
+                let invalid_games = match parser.value().unwrap().to_str() {
+                    Some("abort") => CmbrInvalidGamePolicy::Abort,
+                    Some("skip") => CmbrInvalidGamePolicy::Skip,
+                    Some("keep") => CmbrInvalidGamePolicy::KeepTruncated,
+                    _ => {
+                        eprintln!("Invalid option for invalid-games (Expected `abort`, `skip` or `keep`). Run `cmbrcc --help` for help.");
+                        std::process::exit(1);
+                    }
+                };
+
+                if let Some(CommandE::Pgn2cmbr(ref mut args)) = command {
+                    args.invalid_games = invalid_games;
+                } else {
+                    eprintln!("Invalid option --invalid-games for this subcommand. Run `cmbrcc --help` for help.");
+                    std::process::exit(1);
+                }
+            }
+
+            Long("position-tables") => {
+                let position_tables = parser.value().unwrap().parse();
+
+                if position_tables.is_err() {
+                    eprintln!("Invalid option for position-tables (Expected `true` or `false`). Run `cmbrcc --help` for help.");
+                    std::process::exit(1);
+                }
+
+                if let Some(CommandE::Pgn2cmbr(ref mut args)) = command {
+                    args.position_tables = position_tables.unwrap();
+                } else {
+                    eprintln!("Invalid option --position-tables for this subcommand. Run `cmbrcc --help` for help.");
+                    std::process::exit(1);
+                }
+            }
+
             Value(val) => {
                 if command.is_none() {
                     let cmd = val.to_str().unwrap();
@@ -172,6 +244,10 @@ fn parse_args() -> Cli {
                                 enable_compression: false,
                                 compression_level: 9,
                                 table_mem_limit: 0,
+                                threads: std::thread::available_parallelism()
+                                    .map_or(1, |threads| threads.get()),
+                                invalid_games: CmbrInvalidGamePolicy::default(),
+                                position_tables: true,
                             }));
                         }
 
@@ -210,6 +286,11 @@ fn validate_args(cli: &mut Cli) {
                 exit(1);
             }
 
+            if args.threads == 0 {
+                eprintln!("[ERROR] Expected at least one thread");
+                exit(1);
+            }
+
             if args.input.is_empty() {
                 eprintln!("[ERROR] Expected an input file name\nRun `cmbrcc --help` for help.");
                 exit(1);